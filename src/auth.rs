@@ -0,0 +1,276 @@
+use crate::session::Owner;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::error::Error;
+use std::fmt;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+// One entry of the key file:
+//
+//     [[keys]]
+//     name = "alice"
+//     key = "sk-alice-..."
+//     requests_per_minute = 60
+//     tokens_per_minute = 20000
+//
+//     [[keys]]
+//     name = "ops"
+//     key = "sk-ops-..."
+//     admin = true
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct ApiKey {
+    pub name: String,
+    pub key: String,
+    #[serde(default)]
+    pub requests_per_minute: Option<u32>,
+    // Generated tokens; a reply may overdraw the budget, later requests then wait
+    #[serde(default)]
+    pub tokens_per_minute: Option<u32>,
+    // May read the usage of all keys
+    #[serde(default)]
+    pub admin: bool,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct KeyFile {
+    keys: Vec<ApiKey>,
+}
+
+// Refills continuously up to a minute's worth, so that is also the largest burst.
+#[derive(Debug)]
+struct TokenBucket {
+    capacity: f64,
+    level: f64,
+    updated: Instant,
+}
+
+impl TokenBucket {
+    fn per_minute(limit: u32) -> Self {
+        TokenBucket { capacity: limit as f64, level: limit as f64, updated: Instant::now() }
+    }
+
+    fn refill(&mut self, now: Instant) {
+        let rate = self.capacity / 60.;
+        self.level = (self.level + rate * (now - self.updated).as_secs_f64()).min(self.capacity);
+        self.updated = now;
+    }
+
+    // How long until `amount` could be taken, or take it now.
+    fn take(&mut self, amount: f64, now: Instant) -> Result<(), Duration> {
+        self.refill(now);
+        if self.level >= amount {
+            self.level -= amount;
+            Ok(())
+        } else {
+            Err(Duration::from_secs_f64((amount - self.level) * 60. / self.capacity))
+        }
+    }
+
+    fn charge(&mut self, amount: f64, now: Instant) {
+        self.refill(now);
+        self.level -= amount;
+    }
+}
+
+#[derive(Serialize, Debug, Clone, Default, PartialEq)]
+pub struct Usage {
+    pub requests: u64,
+    pub rejected: u64,
+    pub generated_tokens: u64,
+    pub last_used: Option<u64>,
+}
+
+struct Limits {
+    requests: Option<TokenBucket>,
+    tokens: Option<TokenBucket>,
+    usage: Usage,
+}
+
+// A key together with its buckets and usage so far.
+pub struct KeyState {
+    pub key: ApiKey,
+    limits: Mutex<Limits>,
+}
+
+impl KeyState {
+    fn new(key: ApiKey) -> Self {
+        let limits = Limits {
+            requests: key.requests_per_minute.map(TokenBucket::per_minute),
+            tokens: key.tokens_per_minute.map(TokenBucket::per_minute),
+            usage: Usage::default(),
+        };
+        KeyState { key, limits: Mutex::new(limits) }
+    }
+
+    // Count a request, unless one of the buckets is empty.
+    pub fn admit(&self, now: Instant) -> Result<(), AuthError> {
+        let mut limits = self.limits.lock().unwrap();
+        let limits = &mut *limits;
+        limits.usage.last_used = Some(crate::session::now());
+        let tokens_ready = match &mut limits.tokens {
+            Some(bucket) => bucket.take(0., now),
+            None => Ok(()),
+        };
+        let admitted = tokens_ready.and_then(|()| match &mut limits.requests {
+            Some(bucket) => bucket.take(1., now),
+            None => Ok(()),
+        });
+        match admitted {
+            Ok(()) => {
+                limits.usage.requests += 1;
+                Ok(())
+            }
+            Err(retry_after) => {
+                limits.usage.rejected += 1;
+                Err(AuthError::RateLimited { retry_after })
+            }
+        }
+    }
+
+    // Charge the tokens of a reply.
+    pub fn record_tokens(&self, n: usize) {
+        let mut limits = self.limits.lock().unwrap();
+        limits.usage.generated_tokens += n as u64;
+        if let Some(bucket) = &mut limits.tokens {
+            bucket.charge(n as f64, Instant::now());
+        }
+    }
+
+    pub fn usage(&self) -> Usage {
+        self.limits.lock().unwrap().usage.clone()
+    }
+
+    // Who the sessions started with this key belong to.
+    pub fn owner(&self) -> Owner<'_> {
+        Owner { name: &self.key.name, admin: self.key.admin }
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub enum AuthError {
+    Missing,
+    Invalid,
+    RateLimited { retry_after: Duration },
+}
+
+impl fmt::Display for AuthError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AuthError::Missing => write!(f, "missing bearer token"),
+            AuthError::Invalid => write!(f, "invalid API key"),
+            AuthError::RateLimited { retry_after } => write!(f, "rate limit exceeded, retry in {:.1}s", retry_after.as_secs_f64()),
+        }
+    }
+}
+
+impl Error for AuthError {}
+
+// The API keys the server accepts. Without any, authentication is off and
+// every request is let through.
+#[derive(Default)]
+pub struct Auth {
+    keys: Vec<Arc<KeyState>>,
+}
+
+impl Auth {
+    pub fn new(keys: Vec<ApiKey>) -> Result<Self, Box<dyn Error>> {
+        for (i, key) in keys.iter().enumerate() {
+            if key.key.is_empty() {
+                return Err(format!("key '{}' is empty", key.name).into());
+            }
+            if key.requests_per_minute == Some(0) || key.tokens_per_minute == Some(0) {
+                return Err(format!("key '{}' has a limit of 0, leave it out for no limit", key.name).into());
+            }
+            if keys[..i].iter().any(|other| other.name == key.name || other.key == key.key) {
+                return Err(format!("key '{}' is listed twice", key.name).into());
+            }
+        }
+        Ok(Auth { keys: keys.into_iter().map(|key| Arc::new(KeyState::new(key))).collect() })
+    }
+
+    pub fn from_file(path: &Path) -> Result<Self, Box<dyn Error>> {
+        let text = std::fs::read_to_string(path).map_err(|e| format!("{}: {e}", path.display()))?;
+        let file: KeyFile = toml::from_str(&text).map_err(|e| format!("{}: {e}", path.display()))?;
+        Self::new(file.keys)
+    }
+
+    pub fn enabled(&self) -> bool {
+        !self.keys.is_empty()
+    }
+
+    // Check the value of an `Authorization` header and count the request
+    // against its key.
+    pub fn authorize(&self, header: Option<&str>) -> Result<Arc<KeyState>, AuthError> {
+        let token = header
+            .and_then(|value| value.strip_prefix("Bearer "))
+            .map(str::trim)
+            .ok_or(AuthError::Missing)?;
+        let key = self.keys.iter()
+            .find(|state| constant_time_eq(state.key.key.as_bytes(), token.as_bytes()))
+            .ok_or(AuthError::Invalid)?;
+        key.admit(Instant::now())?;
+        Ok(key.clone())
+    }
+
+    pub fn usage(&self) -> BTreeMap<String, Usage> {
+        self.keys.iter().map(|state| (state.key.name.clone(), state.usage())).collect()
+    }
+}
+
+// Compares without stopping at the first difference, so response times do
+// not tell how much of a guessed key was right.
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |diff, (x, y)| diff | (x ^ y)) == 0
+}
+
+#[test]
+fn test_token_bucket() {
+    let mut bucket = TokenBucket::per_minute(60);
+    let start = bucket.updated;
+    assert_eq!(bucket.take(60., start), Ok(()));
+    assert_eq!(bucket.take(1., start), Err(Duration::from_secs(1)));
+    assert_eq!(bucket.take(1., start + Duration::from_secs(1)), Ok(()));
+    bucket.charge(30., start + Duration::from_secs(1));
+    assert_eq!(bucket.take(0., start + Duration::from_secs(1)), Err(Duration::from_secs(30)));
+    // Never more than a minute's worth
+    assert_eq!(bucket.take(61., start + Duration::from_secs(3600)), Err(Duration::from_secs(1)));
+}
+
+#[test]
+fn test_auth() {
+    let key = |name: &str, requests_per_minute, tokens_per_minute| ApiKey {
+        name: name.into(),
+        key: format!("sk-{name}"),
+        requests_per_minute,
+        tokens_per_minute,
+        admin: false,
+    };
+    assert!(!Auth::default().enabled());
+    assert!(Auth::new(vec![key("a", None, None), key("a", None, None)]).is_err());
+    assert!(Auth::new(vec![key("a", Some(0), None)]).is_err());
+    assert!(Auth::new(vec![key("a", None, Some(0))]).is_err());
+
+    let auth = Auth::new(vec![key("alice", Some(2), None), key("bob", None, Some(10))]).unwrap();
+    assert!(auth.enabled());
+    assert_eq!(auth.authorize(None).err(), Some(AuthError::Missing));
+    assert_eq!(auth.authorize(Some("Basic c2stYWxpY2U=")).err(), Some(AuthError::Missing));
+    assert_eq!(auth.authorize(Some("Bearer sk-carol")).err(), Some(AuthError::Invalid));
+    assert_eq!(auth.authorize(Some("Bearer sk-alice")).unwrap().key.name, "alice");
+    assert!(auth.authorize(Some("Bearer sk-alice")).is_ok());
+    assert!(matches!(auth.authorize(Some("Bearer sk-alice")), Err(AuthError::RateLimited { .. })));
+
+    // A reply that overdraws the token budget holds off the next request
+    let bob = auth.authorize(Some("Bearer sk-bob")).unwrap();
+    bob.record_tokens(25);
+    assert!(matches!(auth.authorize(Some("Bearer sk-bob")), Err(AuthError::RateLimited { .. })));
+
+    let usage = auth.usage();
+    assert_eq!(usage["alice"].requests, 2);
+    assert_eq!(usage["alice"].rejected, 1);
+    assert_eq!(usage["bob"].generated_tokens, 25);
+    assert!(usage["bob"].last_used.is_some());
+}
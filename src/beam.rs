@@ -0,0 +1,247 @@
+use serde::{Deserialize, Serialize};
+use crate::kvcache::{KVCache, KVElem};
+use crate::metrics::{GaugeGuard, TokenTimer, METRICS};
+use crate::model::{GenerateError, Llama};
+use crate::operators::{self as OP, ToF32};
+use crate::params::Load;
+use crate::stop::{FinishReason, StopCriteria};
+use crate::tensor::Tensor;
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub struct BeamParams {
+    // Finished sequences are ranked by logprob / len^length_penalty, values
+    // above 0 favour longer sequences.
+    #[serde(default = "default_length_penalty")]
+    pub length_penalty: f32,
+    // Stop as soon as `n_best` sequences are finished instead of when no live
+    // beam can beat them anymore.
+    #[serde(default)]
+    pub early_stopping: bool,
+    #[serde(default = "default_n_best")]
+    pub n_best: usize,
+}
+
+impl Default for BeamParams {
+    fn default() -> Self {
+        BeamParams { length_penalty: default_length_penalty(), early_stopping: false, n_best: default_n_best() }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Hypothesis {
+    pub tokens: Vec<u32>, // generated tokens, without the prompt and eos
+    pub logprob: f32,     // cumulative log-probability of the tokens (and eos if it ended)
+    pub score: f32,       // length-penalized logprob used for ranking
+    pub finish_reason: FinishReason,
+}
+
+struct Beam<C> {
+    tokens: Vec<u32>,
+    logprob: f32,
+    logprobs: Vec<f32>, // of the next token
+    cache: Option<BeamCache<C>>,
+}
+
+// A beam's cache, counted in `kv_cache_bytes` for as long as it lives.
+struct BeamCache<C> {
+    cache: KVCache<C>,
+    _bytes: GaugeGuard,
+}
+
+impl<C: KVElem> BeamCache<C> {
+    fn new(cache: KVCache<C>) -> Self {
+        let bytes = GaugeGuard::add(&METRICS.kv_cache_bytes, cache.bytes() as i64);
+        BeamCache { cache, _bytes: bytes }
+    }
+}
+
+// Beam search decoding over `beam_width` beams. Beams extending the same
+// parent share its prefix in a forked cache, only the last child takes the
+// parent's cache over. A beam ends at an eos or stop token, which is left out,
+// or with the token completing a stop string; all of them end once `stop` is
+// interrupted.
+pub fn beam_search<T, C>(
+    llama: &Llama<T>,
+    token_ids: &[u32],
+    beam_width: usize,
+    max_len: usize,
+    params: &BeamParams,
+    stop: &StopCriteria,
+) -> Result<Vec<Hypothesis>, GenerateError>
+where
+    T: Default + Copy + ToF32 + Load,
+    C: KVElem,
+{
+    assert!(beam_width >= 1 && params.n_best >= 1);
+    if token_ids.len() >= llama.max_seq_len() {
+        return Err(GenerateError::ContextOverflow { len: token_ids.len() + 1, max_seq_len: llama.max_seq_len() });
+    }
+    let max_len = max_len.min(llama.max_seq_len() - token_ids.len());
+    let score = |logprob: f32, len: usize| logprob / (len.max(1) as f32).powf(params.length_penalty);
+
+    let mut timer = TokenTimer::start();
+    let mut cache = BeamCache::new(llama.new_cache::<C>());
+    let logits = llama.forward(&Tensor::new(token_ids.to_vec(), &[token_ids.len()]), &mut cache.cache);
+    let mut beams = vec![Beam { tokens: Vec::new(), logprob: 0., logprobs: OP::log_softmax(logits.data()), cache: Some(cache) }];
+    let mut finished = Vec::<Hypothesis>::new();
+    let mut interrupted = None;
+
+    for step in 1..=max_len {
+        if beams.is_empty() {
+            break;
+        }
+        if let Some(reason) = stop.interrupted() {
+            interrupted = Some(reason);
+            break;
+        }
+        // Best continuations over all beams; 2 * beam_width per beam ensures
+        // enough of them are left when some end with eos
+        let mut cands = Vec::new();
+        for (b, beam) in beams.iter().enumerate() {
+            let mut top = beam.logprobs.iter().enumerate().map(|(tok, lp)| (tok as u32, *lp)).collect::<Vec<_>>();
+            let n = (2 * beam_width).min(top.len());
+            top.select_nth_unstable_by(n - 1, |a, b| b.1.total_cmp(&a.1));
+            cands.extend(top[..n].iter().map(|&(tok, lp)| (b, tok, beam.logprob + lp)));
+        }
+        cands.sort_by(|a, b| b.2.total_cmp(&a.2));
+        timer.tokens(1);
+
+        let mut next = Vec::<(usize, u32, f32)>::new();
+        for (rank, &(b, tok, logprob)) in cands.iter().enumerate() {
+            if llama.is_eos(tok) || stop.is_stop_token(tok) {
+                if rank < beam_width {
+                    let tokens = beams[b].tokens.clone();
+                    let finish_reason = FinishReason::StopToken(tok);
+                    finished.push(Hypothesis { score: score(logprob, tokens.len() + 1), tokens, logprob, finish_reason });
+                }
+            } else {
+                next.push((b, tok, logprob));
+            }
+            if next.len() == beam_width {
+                break;
+            }
+        }
+        finished.sort_by(|a, b| b.score.total_cmp(&a.score));
+        finished.truncate(params.n_best);
+
+        if finished.len() == params.n_best {
+            let best_live = next.first().map_or(f32::NEG_INFINITY, |c| score(c.2, step));
+            if params.early_stopping || best_live <= finished.last().unwrap().score {
+                timer.finish(finished[0].tokens.len());
+                return Ok(finished);
+            }
+        }
+
+        let mut children = vec![0; beams.len()];
+        next.iter().for_each(|&(b, _, _)| children[b] += 1);
+        let mut new_beams = Vec::with_capacity(next.len());
+        for (b, tok, logprob) in next {
+            children[b] -= 1;
+            let parent = &mut beams[b];
+            let mut cache = if children[b] == 0 {
+                parent.cache.take().unwrap()
+            } else {
+                BeamCache::new(parent.cache.as_ref().unwrap().cache.fork())
+            };
+            let mut tokens = parent.tokens.clone();
+            tokens.push(tok);
+            if let Some(i) = stop.find_string(&tokens) {
+                let finish_reason = FinishReason::StopString(i);
+                finished.push(Hypothesis { score: score(logprob, tokens.len()), tokens, logprob, finish_reason });
+                continue;
+            }
+            let logprobs = if step < max_len {
+                OP::log_softmax(llama.forward(&Tensor::new(vec![tok], &[1]), &mut cache.cache).data())
+            } else {
+                Vec::new()
+            };
+            new_beams.push(Beam { tokens, logprob, logprobs, cache: Some(cache) });
+        }
+        beams = new_beams;
+    }
+
+    // Out of length or interrupted: the live beams compete with the finished ones
+    finished.extend(beams.into_iter().map(|beam| Hypothesis {
+        score: score(beam.logprob, beam.tokens.len()),
+        tokens: beam.tokens,
+        logprob: beam.logprob,
+        finish_reason: interrupted.unwrap_or(FinishReason::Length),
+    }));
+    finished.sort_by(|a, b| b.score.total_cmp(&a.score));
+    finished.truncate(params.n_best);
+    timer.finish(finished.first().map_or(0, |best| best.tokens.len()));
+    Ok(finished)
+}
+
+#[inline(always)]
+const fn default_length_penalty() -> f32 {
+    1.
+}
+
+#[inline(always)]
+const fn default_n_best() -> usize {
+    1
+}
+
+#[test]
+fn test_beam_search() {
+    use std::path::PathBuf;
+    use crate::model::ContextOverflow;
+    use crate::sampler::Sampler;
+    let project_dir = env!("CARGO_MANIFEST_DIR");
+    let model_dir = PathBuf::from(project_dir).join("models").join("story");
+    let llama = Llama::<f32>::from_safetensors(model_dir);
+    let prompt = [1u32, 400, 35];
+    let params = BeamParams { length_penalty: 0., early_stopping: false, n_best: 1 };
+    let none = &StopCriteria::none();
+
+    // A single beam is greedy decoding
+    let greedy = llama.generate::<f32>(&prompt, 12, &mut Sampler::new(0.8, 1, 1.), ContextOverflow::Error, &StopCriteria::none(), None).unwrap().tokens;
+    let single = beam_search::<f32, f32>(&llama, &prompt, 1, 12, &params, none).unwrap();
+    assert_eq!(single[0].tokens, greedy);
+
+    // Wider beams find sequences at least as likely, ranked best first
+    let params = BeamParams { n_best: 3, ..params };
+    let best = beam_search::<f32, f32>(&llama, &prompt, 4, 12, &params, none).unwrap();
+    assert_eq!(best.len(), 3);
+    assert!(best[0].logprob >= single[0].logprob);
+    assert!(best.windows(2).all(|w| w[0].score >= w[1].score));
+
+    // Cumulative log-probabilities match rescoring the sequence in one pass
+    let mut ids = prompt.to_vec();
+    ids.extend(&best[1].tokens);
+    let logits = llama.forward_all(&Tensor::new(ids.clone(), &[ids.len()]), &mut llama.new_cache::<f32>());
+    let vocab = logits.shape()[1];
+    let mut logprob: f32 = (prompt.len()..ids.len())
+        .map(|i| OP::log_softmax(&logits.data()[(i - 1) * vocab..][..vocab])[ids[i] as usize])
+        .sum();
+    if best[1].tokens.len() < 12 {
+        logprob += OP::log_softmax(&logits.data()[(ids.len() - 1) * vocab..])[llama.eos_token_ids()[0] as usize];
+    }
+    assert!((logprob - best[1].logprob).abs() < 1e-3);
+}
+
+#[test]
+fn test_beam_search_stops() {
+    use std::path::PathBuf;
+    use crate::stop::CancelToken;
+    let project_dir = env!("CARGO_MANIFEST_DIR");
+    let model_dir = PathBuf::from(project_dir).join("models").join("story");
+    let llama = Llama::<f32>::from_safetensors(model_dir);
+    let prompt = [1u32, 400, 35];
+    let params = BeamParams::default();
+    let greedy = beam_search::<f32, f32>(&llama, &prompt, 1, 12, &params, &StopCriteria::none()).unwrap().remove(0);
+
+    // A stop token ends the beam and is left out
+    let stop = StopCriteria::new(vec![greedy.tokens[3]], Vec::new(), |_| String::new());
+    let stopped = beam_search::<f32, f32>(&llama, &prompt, 1, 12, &params, &stop).unwrap().remove(0);
+    assert_eq!(stopped.tokens, greedy.tokens[..3]);
+    assert_eq!(stopped.finish_reason, FinishReason::StopToken(greedy.tokens[3]));
+
+    let cancel = CancelToken::new();
+    cancel.cancel();
+    let stop = StopCriteria::none().with_cancel(cancel);
+    let cancelled = beam_search::<f32, f32>(&llama, &prompt, 2, 12, &params, &stop).unwrap().remove(0);
+    assert!(cancelled.tokens.is_empty());
+    assert_eq!(cancelled.finish_reason, FinishReason::Cancelled);
+}
@@ -1,8 +1,24 @@
-use serde;
+// A token id field that may hold a single id or a list of them
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone)]
+#[serde(untagged)]
+pub(crate) enum TokenIds {
+    One(u32),
+    Many(Vec<u32>),
+}
+
+impl TokenIds {
+    pub fn to_vec(&self) -> Vec<u32> {
+        match self {
+            TokenIds::One(id) => vec![*id],
+            TokenIds::Many(ids) => ids.clone(),
+        }
+    }
+}
+
 #[derive(serde::Serialize, serde::Deserialize, Debug)]
 pub(crate) struct LlamaConfigJson {
     pub bos_token_id: u32,
-    pub eos_token_id: u32,
+    pub eos_token_id: TokenIds,
     pub hidden_size: usize,
     pub intermediate_size: usize,
     pub max_position_embeddings: usize,
@@ -19,6 +35,13 @@ pub(crate) struct LlamaConfigJson {
     pub tie_word_embeddings: bool,
 }
 
+// generation_config.json, only the fields used here
+#[derive(serde::Serialize, serde::Deserialize, Debug)]
+pub(crate) struct GenerationConfigJson {
+    #[serde(default)]
+    pub eos_token_id: Option<TokenIds>,
+}
+
 #[inline(always)]
 const fn default_rms_norm_eps() -> f32 {
     1e-5
@@ -0,0 +1,66 @@
+use crate::stop::CancelToken;
+use actix_web::rt;
+use socket2::{SockRef, Socket};
+use std::any::Any;
+use std::io::ErrorKind;
+use std::mem::MaybeUninit;
+use std::sync::Arc;
+use std::time::Duration;
+
+// A second handle on the client's socket. actix only notices a client is gone
+// when it writes the response, which for a plain reply is after generating it,
+// so handlers look at the socket themselves.
+#[derive(Clone)]
+pub struct PeerSocket(Arc<Socket>);
+
+impl PeerSocket {
+    // For `HttpServer::on_connect`.
+    pub fn from_connection(connection: &dyn Any) -> Option<Self> {
+        let stream = connection.downcast_ref::<rt::net::TcpStream>()?;
+        SockRef::from(stream).try_clone().ok().map(|socket| PeerSocket(Arc::new(socket)))
+    }
+
+    // The client closed the connection. Peeking leaves pipelined requests
+    // in place, and the socket is non-blocking like the one it was cloned from.
+    pub fn is_closed(&self) -> bool {
+        let mut buf = [MaybeUninit::uninit(); 1];
+        match self.0.peek(&mut buf) {
+            Ok(0) => true,
+            Ok(_) => false,
+            Err(e) => !matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::Interrupted),
+        }
+    }
+
+    // Cancel `cancel` once the client goes away; the watch ends along with
+    // the generation, i.e. when the token fires for any reason.
+    pub fn watch(self, cancel: CancelToken) {
+        rt::spawn(async move {
+            while cancel.check().is_none() {
+                rt::time::sleep(Duration::from_millis(200)).await;
+                if self.is_closed() {
+                    cancel.cancel();
+                }
+            }
+        });
+    }
+}
+
+#[test]
+fn test_peer_socket() {
+    use std::io::Write;
+    use std::net::{TcpListener, TcpStream};
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let mut client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+    let (server, _) = listener.accept().unwrap();
+    server.set_nonblocking(true).unwrap();
+    let peer = PeerSocket(Arc::new(SockRef::from(&server).try_clone().unwrap()));
+    assert!(!peer.is_closed());
+    client.write_all(b"GET /").unwrap();
+    assert!(!peer.is_closed());
+    drop(client);
+    std::thread::sleep(Duration::from_millis(50));
+    // Unread data comes first, so close only shows once it has been consumed
+    let mut buf = [0; 5];
+    std::io::Read::read_exact(&mut &server, &mut buf).unwrap();
+    assert!(peer.is_closed());
+}
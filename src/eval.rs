@@ -0,0 +1,154 @@
+use std::error::Error;
+use std::io;
+use std::path::Path;
+use half::bf16;
+use tokenizers::Tokenizer;
+use crate::kvcache::{CacheDtype, KVElem};
+use crate::model::Llama;
+use crate::operators::{self as OP, ToF32};
+use crate::params::Load;
+use crate::registry::{LoadedModel, Weights};
+use crate::settings::{EvalArgs, Settings};
+use crate::tensor::Tensor;
+
+pub struct Evaluation {
+    pub token_logprobs: Vec<f32>, // log p(token_i | tokens_<i) for every scored token
+}
+
+impl Evaluation {
+    pub fn log_likelihood(&self) -> f64 {
+        self.token_logprobs.iter().map(|&lp| lp as f64).sum()
+    }
+
+    pub fn perplexity(&self) -> f64 {
+        (-self.log_likelihood() / self.token_logprobs.len() as f64).exp()
+    }
+}
+
+// Score `token_ids` with a sliding window of `window` tokens advanced by
+// `stride`. Each window only scores the tokens the previous one did not, the
+// remaining ones serve as context, so every token but the first is scored once.
+// A window never starts past the last token already scored, so even with
+// `stride == window` each one has at least a token of context.
+pub fn evaluate<T, C>(llama: &Llama<T>, token_ids: &[u32], window: usize, stride: usize) -> Evaluation
+where
+    T: Default + Copy + ToF32 + Load,
+    C: KVElem,
+{
+    assert!(window >= 2 && window <= llama.max_seq_len());
+    assert!(stride >= 1 && stride <= window);
+    let mut token_logprobs = Vec::new();
+    let mut scored = 1; // the first token has no context to be predicted from
+    let mut start = 0;
+    while scored < token_ids.len() {
+        let end = (start + window).min(token_ids.len());
+        let input = &token_ids[start..end];
+        let mut cache = llama.new_cache::<C>();
+        let logits = llama.forward_all(&Tensor::new(input.to_vec(), &[input.len()]), &mut cache);
+        let vocab = logits.shape()[1];
+        for (i, &id) in token_ids.iter().enumerate().take(end).skip(scored) {
+            let row = &logits.data()[(i - start - 1) * vocab..][..vocab];
+            token_logprobs.push(OP::log_softmax(row)[id as usize]);
+        }
+        scored = end;
+        start = (start + stride).min(scored - 1);
+    }
+    Evaluation { token_logprobs }
+}
+
+// Tokenize a text file and evaluate it, see `evaluate`.
+pub fn evaluate_file<T, C>(
+    llama: &Llama<T>,
+    tokenizer: &Tokenizer,
+    path: impl AsRef<Path>,
+    window: usize,
+    stride: usize,
+) -> io::Result<Evaluation>
+where
+    T: Default + Copy + ToF32 + Load,
+    C: KVElem,
+{
+    let text = std::fs::read_to_string(path)?;
+    let encoding = tokenizer
+        .encode(text, true)
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+    Ok(evaluate::<T, C>(llama, encoding.get_ids(), window, stride))
+}
+
+// The `eval` command: print the perplexity of a model on a text file, to
+// compare weight and cache dtypes.
+pub fn run(args: &EvalArgs, settings: &Settings) -> Result<(), Box<dyn Error>> {
+    let dir = args.model.as_ref().unwrap_or(&settings.models.story);
+    let model = LoadedModel::load("eval", dir, settings.compute.dtype)?;
+    let context_length = model.info().context_length;
+    let window = args.window.unwrap_or(context_length);
+    let stride = args.stride.unwrap_or(window / 2);
+    if window < 2 || window > context_length {
+        return Err(format!("the window must be between 2 and the context length of {context_length}").into());
+    }
+    if stride < 1 || stride > window {
+        return Err(format!("the stride must be between 1 and the window of {window}").into());
+    }
+    let evaluation = match &model.weights {
+        Weights::F32(llama) => evaluate_with(llama, &model.tokenizer, args, window, stride)?,
+        Weights::BF16(llama) => evaluate_with(llama, &model.tokenizer, args, window, stride)?,
+    };
+    println!("model:          {}", dir.display());
+    println!("weights:        {:?}", model.dtype);
+    println!("kv cache:       {:?}", args.kv_cache_dtype);
+    println!("window, stride: {window}, {stride}");
+    println!("tokens scored:  {}", evaluation.token_logprobs.len());
+    println!("log-likelihood: {:.4}", evaluation.log_likelihood());
+    println!("perplexity:     {:.4}", evaluation.perplexity());
+    Ok(())
+}
+
+fn evaluate_with<T>(llama: &Llama<T>, tokenizer: &Tokenizer, args: &EvalArgs, window: usize, stride: usize) -> io::Result<Evaluation>
+where T: Default + Copy + ToF32 + Load
+{
+    match args.kv_cache_dtype {
+        CacheDtype::F32 => evaluate_file::<T, f32>(llama, tokenizer, &args.path, window, stride),
+        CacheDtype::BF16 => evaluate_file::<T, bf16>(llama, tokenizer, &args.path, window, stride),
+        CacheDtype::Int8 => evaluate_file::<T, i8>(llama, tokenizer, &args.path, window, stride),
+    }
+}
+
+#[test]
+fn test_perplexity() {
+    use std::path::PathBuf;
+    let project_dir = env!("CARGO_MANIFEST_DIR");
+    let model_dir = PathBuf::from(project_dir).join("models").join("story");
+    let llama = Llama::<f32>::from_safetensors(&model_dir);
+    let tokenizer = Tokenizer::from_file(model_dir.join("tokenizer.json")).unwrap();
+    let path = std::env::temp_dir().join(format!("story-eval-{}.txt", std::process::id()));
+    std::fs::write(&path, "Once upon a time, there was a little girl named Lily. She liked to play outside with her dog. One day, she saw a big tree in the park.").unwrap();
+
+    let full = evaluate_file::<f32, f32>(&llama, &tokenizer, &path, 512, 512).unwrap();
+    let sliding = evaluate_file::<f32, f32>(&llama, &tokenizer, &path, 16, 8).unwrap();
+    std::fs::remove_file(&path).unwrap();
+
+    let n_tokens = tokenizer.encode("Once upon a time, there was a little girl named Lily. She liked to play outside with her dog. One day, she saw a big tree in the park.", true).unwrap().len();
+    assert_eq!(full.token_logprobs.len(), n_tokens - 1);
+    assert_eq!(sliding.token_logprobs.len(), n_tokens - 1);
+    // The story model knows this kind of text well
+    assert!(full.perplexity() > 1. && full.perplexity() < 50.);
+    // Tokens in a fresh window see less context but are still scored
+    assert_eq!(full.token_logprobs[..15], sliding.token_logprobs[..15]);
+    assert!(sliding.perplexity() >= full.perplexity() * 0.8);
+}
+
+#[test]
+fn test_stride_up_to_window() {
+    use std::path::PathBuf;
+    let project_dir = env!("CARGO_MANIFEST_DIR");
+    let model_dir = PathBuf::from(project_dir).join("models").join("story");
+    let llama = Llama::<f32>::from_safetensors(&model_dir);
+    let tokenizer = Tokenizer::from_file(model_dir.join("tokenizer.json")).unwrap();
+    let ids = tokenizer.encode("Once upon a time, there was a little girl named Lily. She liked to play outside with her dog.", true).unwrap().get_ids().to_vec();
+    assert!(ids.len() > 8);
+    for stride in [7, 8] {
+        let evaluation = evaluate::<f32, f32>(&llama, &ids, 8, stride);
+        assert_eq!(evaluation.token_logprobs.len(), ids.len() - 1);
+        assert!(evaluation.perplexity().is_finite());
+    }
+}
@@ -0,0 +1,799 @@
+use crate::sampler::LogitProcessor;
+use serde_json::Value;
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::Arc;
+
+// A GBNF-style grammar, as used by llama.cpp:
+//
+//     root  ::= "yes" | "no" | digit+
+//     digit ::= [0-9]   # comments run to the end of the line
+//
+// Supported are string literals, character classes (`[a-z]`, `[^"\\]`), `.`,
+// rule references, grouping and the `*`, `+` and `?` operators. A rule ends
+// at the end of its line unless the line ends in `|` or inside parentheses.
+// Groups and repetitions are desugared into anonymous rules, so the grammar
+// itself is just alternatives of sequences of characters and rule references.
+#[derive(Debug, Clone, PartialEq)]
+enum Elem {
+    Chars { ranges: Vec<(char, char)>, negated: bool },
+    Rule(usize),
+}
+
+impl Elem {
+    fn matches(&self, c: char) -> bool {
+        match self {
+            Elem::Chars { ranges, negated } => ranges.iter().any(|&(lo, hi)| lo <= c && c <= hi) != *negated,
+            Elem::Rule(_) => false,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct Grammar {
+    rules: Vec<Vec<Vec<Elem>>>, // rule -> alternatives -> sequence
+    root: usize,
+}
+
+#[derive(Debug, PartialEq)]
+pub struct GrammarError {
+    pub pos: usize,
+    pub message: String,
+}
+
+impl fmt::Display for GrammarError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "invalid grammar at offset {}: {}", self.pos, self.message)
+    }
+}
+
+impl std::error::Error for GrammarError {}
+
+struct Parser {
+    src: Vec<char>,
+    pos: usize,
+    names: HashMap<String, usize>,
+    rules: Vec<Option<Vec<Vec<Elem>>>>,
+    first_use: Vec<usize>, // where each rule was first mentioned, for errors
+}
+
+impl Parser {
+    fn error<R>(&self, message: impl Into<String>) -> Result<R, GrammarError> {
+        Err(GrammarError { pos: self.pos, message: message.into() })
+    }
+
+    fn peek(&self) -> Option<char> {
+        self.src.get(self.pos).copied()
+    }
+
+    fn skip_space(&mut self, newlines: bool) {
+        while let Some(c) = self.peek() {
+            match c {
+                ' ' | '\t' | '\r' => self.pos += 1,
+                '\n' if newlines => self.pos += 1,
+                '#' => {
+                    while self.peek().is_some_and(|c| c != '\n') {
+                        self.pos += 1;
+                    }
+                }
+                _ => break,
+            }
+        }
+    }
+
+    fn rule_id(&mut self, name: &str) -> usize {
+        if let Some(&id) = self.names.get(name) {
+            return id;
+        }
+        self.names.insert(name.to_string(), self.rules.len());
+        self.rules.push(None);
+        self.first_use.push(self.pos);
+        self.rules.len() - 1
+    }
+
+    fn anonymous_rule(&mut self, alternatives: Vec<Vec<Elem>>) -> usize {
+        self.rules.push(Some(alternatives));
+        self.first_use.push(self.pos);
+        self.rules.len() - 1
+    }
+
+    fn parse_name(&mut self) -> Result<String, GrammarError> {
+        let start = self.pos;
+        while self.peek().is_some_and(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_') {
+            self.pos += 1;
+        }
+        if start == self.pos {
+            return self.error("expected a rule name");
+        }
+        Ok(self.src[start..self.pos].iter().collect())
+    }
+
+    fn parse_char(&mut self) -> Result<char, GrammarError> {
+        let Some(c) = self.peek() else {
+            return self.error("unexpected end of input");
+        };
+        self.pos += 1;
+        if c != '\\' {
+            return Ok(c);
+        }
+        let Some(e) = self.peek() else {
+            return self.error("unexpected end of input");
+        };
+        self.pos += 1;
+        let hex_len = match e {
+            'n' => return Ok('\n'),
+            'r' => return Ok('\r'),
+            't' => return Ok('\t'),
+            'x' => 2,
+            'u' => 4,
+            'U' => 8,
+            _ => return Ok(e),
+        };
+        let digits: String = self.src.iter().skip(self.pos).take(hex_len).collect();
+        match u32::from_str_radix(&digits, 16).ok().and_then(char::from_u32) {
+            Some(c) if digits.len() == hex_len => {
+                self.pos += hex_len;
+                Ok(c)
+            }
+            _ => self.error("invalid escape sequence"),
+        }
+    }
+
+    fn parse_alternatives(&mut self, nested: bool) -> Result<Vec<Vec<Elem>>, GrammarError> {
+        let mut alternatives = vec![self.parse_sequence(nested)?];
+        while self.peek() == Some('|') {
+            self.pos += 1;
+            self.skip_space(true);
+            alternatives.push(self.parse_sequence(nested)?);
+        }
+        Ok(alternatives)
+    }
+
+    fn parse_sequence(&mut self, nested: bool) -> Result<Vec<Elem>, GrammarError> {
+        let mut seq = Vec::new();
+        let mut last_start = 0; // where the element a repetition applies to begins
+        while let Some(c) = self.peek() {
+            match c {
+                '"' => {
+                    self.pos += 1;
+                    last_start = seq.len();
+                    while self.peek() != Some('"') {
+                        let c = self.parse_char()?;
+                        seq.push(Elem::Chars { ranges: vec![(c, c)], negated: false });
+                    }
+                    self.pos += 1;
+                }
+                '[' => {
+                    self.pos += 1;
+                    last_start = seq.len();
+                    let negated = self.peek() == Some('^');
+                    if negated {
+                        self.pos += 1;
+                    }
+                    let mut ranges = Vec::new();
+                    while self.peek() != Some(']') {
+                        let lo = self.parse_char()?;
+                        let hi = if self.peek() == Some('-') && self.src.get(self.pos + 1) != Some(&']') {
+                            self.pos += 1;
+                            self.parse_char()?
+                        } else {
+                            lo
+                        };
+                        ranges.push((lo, hi));
+                    }
+                    self.pos += 1;
+                    seq.push(Elem::Chars { ranges, negated });
+                }
+                '.' => {
+                    self.pos += 1;
+                    last_start = seq.len();
+                    seq.push(Elem::Chars { ranges: Vec::new(), negated: true });
+                }
+                '(' => {
+                    self.pos += 1;
+                    self.skip_space(true);
+                    let alternatives = self.parse_alternatives(true)?;
+                    if self.peek() != Some(')') {
+                        return self.error("expected ')'");
+                    }
+                    self.pos += 1;
+                    last_start = seq.len();
+                    seq.push(Elem::Rule(self.anonymous_rule(alternatives)));
+                }
+                '*' | '+' | '?' => {
+                    if last_start == seq.len() {
+                        return self.error(format!("'{c}' must follow an element"));
+                    }
+                    self.pos += 1;
+                    let item = seq.split_off(last_start);
+                    // Reserve the id first so that the rule can refer to itself
+                    let id = self.anonymous_rule(Vec::new());
+                    let recurse = item.iter().cloned().chain([Elem::Rule(id)]).collect();
+                    self.rules[id] = Some(match c {
+                        '*' => vec![recurse, vec![]],
+                        '+' => vec![recurse, item],
+                        _ => vec![item, vec![]],
+                    });
+                    seq.push(Elem::Rule(id));
+                }
+                c if c.is_ascii_alphanumeric() || c == '-' || c == '_' => {
+                    let name = self.parse_name()?;
+                    last_start = seq.len();
+                    seq.push(Elem::Rule(self.rule_id(&name)));
+                }
+                _ => break,
+            }
+            self.skip_space(nested);
+        }
+        Ok(seq)
+    }
+}
+
+impl Grammar {
+    pub fn parse(src: &str) -> Result<Self, GrammarError> {
+        let mut parser = Parser {
+            src: src.chars().collect(),
+            pos: 0,
+            names: HashMap::new(),
+            rules: Vec::new(),
+            first_use: Vec::new(),
+        };
+        loop {
+            parser.skip_space(true);
+            if parser.peek().is_none() {
+                break;
+            }
+            let name = parser.parse_name()?;
+            let id = parser.rule_id(&name);
+            parser.skip_space(false);
+            if !parser.src[parser.pos..].starts_with(&[':', ':', '=']) {
+                return parser.error("expected '::='");
+            }
+            parser.pos += 3;
+            parser.skip_space(true);
+            let alternatives = parser.parse_alternatives(false)?;
+            if parser.rules[id].is_some() {
+                return parser.error(format!("rule '{name}' is defined twice"));
+            }
+            parser.rules[id] = Some(alternatives);
+            if parser.peek().is_some_and(|c| c != '\n') {
+                return parser.error("unexpected character");
+            }
+        }
+        let Some(&root) = parser.names.get("root") else {
+            return parser.error("missing 'root' rule");
+        };
+        let mut rules = Vec::with_capacity(parser.rules.len());
+        for (id, rule) in parser.rules.into_iter().enumerate() {
+            match rule {
+                Some(rule) => rules.push(rule),
+                None => {
+                    let name = parser.names.iter().find(|(_, &i)| i == id).unwrap().0;
+                    return Err(GrammarError {
+                        pos: parser.first_use[id],
+                        message: format!("undefined rule '{name}'"),
+                    });
+                }
+            }
+        }
+        let grammar = Grammar { rules, root };
+        if let Some(id) = grammar.left_recursive_rule() {
+            return Err(GrammarError { pos: parser.first_use[id], message: "left recursion is not supported".into() });
+        }
+        Ok(grammar)
+    }
+
+    // A rule that can reach itself without consuming a character would make
+    // `expand` loop forever, so such grammars are rejected up front.
+    fn left_recursive_rule(&self) -> Option<usize> {
+        let mut nullable = vec![false; self.rules.len()];
+        let mut changed = true;
+        while changed {
+            changed = false;
+            for (id, rule) in self.rules.iter().enumerate() {
+                if !nullable[id] && rule.iter().any(|seq| seq.iter().all(|e| matches!(e, Elem::Rule(r) if nullable[*r]))) {
+                    nullable[id] = true;
+                    changed = true;
+                }
+            }
+        }
+        // Rules each rule may start with, directly
+        let leading: Vec<Vec<usize>> = self
+            .rules
+            .iter()
+            .map(|rule| {
+                let mut leading = Vec::new();
+                for seq in rule {
+                    for elem in seq {
+                        match elem {
+                            Elem::Rule(r) => leading.push(*r),
+                            Elem::Chars { .. } => break,
+                        }
+                        if !matches!(elem, Elem::Rule(r) if nullable[*r]) {
+                            break;
+                        }
+                    }
+                }
+                leading
+            })
+            .collect();
+        (0..self.rules.len()).find(|&start| {
+            let mut seen = vec![false; self.rules.len()];
+            let mut todo = leading[start].clone();
+            while let Some(id) = todo.pop() {
+                if id == start {
+                    return true;
+                }
+                if !std::mem::replace(&mut seen[id], true) {
+                    todo.extend(&leading[id]);
+                }
+            }
+            false
+        })
+    }
+
+    // Push every way of reaching a character element from `stack`. An empty
+    // stack means the input so far is a complete match.
+    fn expand(&self, mut stack: Stack, out: &mut Vec<Stack>) {
+        let Some(&(rule, alt, i)) = stack.last() else {
+            out.push(stack);
+            return;
+        };
+        match &self.rules[rule][alt][i] {
+            Elem::Chars { .. } => out.push(stack),
+            Elem::Rule(sub) => {
+                stack.pop();
+                // Tail calls leave nothing to come back to, which keeps
+                // the stacks of repetitions from growing
+                if i + 1 < self.rules[rule][alt].len() {
+                    stack.push((rule, alt, i + 1));
+                }
+                for (alt, seq) in self.rules[*sub].iter().enumerate() {
+                    let mut stack = stack.clone();
+                    if !seq.is_empty() {
+                        stack.push((*sub, alt, 0));
+                    }
+                    self.expand(stack, out);
+                }
+            }
+        }
+    }
+
+    fn accept(&self, stacks: &[Stack], c: char) -> Vec<Stack> {
+        let mut out = Vec::new();
+        for stack in stacks {
+            let Some(&(rule, alt, i)) = stack.last() else {
+                continue;
+            };
+            if self.rules[rule][alt][i].matches(c) {
+                let mut stack = stack.clone();
+                stack.pop();
+                if i + 1 < self.rules[rule][alt].len() {
+                    stack.push((rule, alt, i + 1));
+                }
+                self.expand(stack, &mut out);
+            }
+        }
+        out.sort_unstable();
+        out.dedup();
+        out
+    }
+}
+
+// Positions still to be matched, innermost last: (rule, alternative, element).
+type Stack = Vec<(usize, usize, usize)>;
+
+// Tracks every way the text accepted so far can continue under a grammar.
+#[derive(Clone)]
+pub struct GrammarMatcher {
+    grammar: Arc<Grammar>,
+    stacks: Vec<Stack>,
+}
+
+impl GrammarMatcher {
+    pub fn new(grammar: Arc<Grammar>) -> Self {
+        let mut stacks = Vec::new();
+        for (alt, seq) in grammar.rules[grammar.root].iter().enumerate() {
+            grammar.expand(if seq.is_empty() { vec![] } else { vec![(grammar.root, alt, 0)] }, &mut stacks);
+        }
+        stacks.sort_unstable();
+        stacks.dedup();
+        GrammarMatcher { grammar, stacks }
+    }
+
+    // Consume `text`; false (leaving the matcher dead) if the grammar rejects it.
+    pub fn accept_str(&mut self, text: &str) -> bool {
+        for c in text.chars() {
+            self.stacks = self.grammar.accept(&self.stacks, c);
+            if self.stacks.is_empty() {
+                return false;
+            }
+        }
+        true
+    }
+
+    // Whether the grammar could accept `text` next.
+    #[cfg(test)]
+    pub fn allows(&self, text: &str) -> bool {
+        let mut stacks = self.stacks.clone();
+        for c in text.chars() {
+            stacks = self.grammar.accept(&stacks, c);
+            if stacks.is_empty() {
+                return false;
+            }
+        }
+        true
+    }
+
+    // Whether the text accepted so far is a complete sentence of the grammar.
+    pub fn is_complete(&self) -> bool {
+        self.stacks.iter().any(|s| s.is_empty())
+    }
+
+    // Call `allow` with every token of `vocab` the grammar could accept next.
+    pub fn allowed_tokens(&self, vocab: &TokenTrie, mut allow: impl FnMut(u32)) {
+        vocab.visit(&self.grammar, 0, &self.stacks, &mut allow);
+    }
+}
+
+// The text of every token, also arranged as a trie so that tokens sharing a
+// prefix are matched against a grammar once for all of them.
+pub struct TokenTrie {
+    pieces: Vec<String>,
+    nodes: Vec<TrieNode>,
+}
+
+#[derive(Default)]
+struct TrieNode {
+    children: Vec<(char, usize)>, // sorted by character
+    tokens: Vec<u32>,             // whose text ends here
+}
+
+impl TokenTrie {
+    // `pieces` holds the text of each token id, see `GrammarProcessor`.
+    pub fn new(pieces: Vec<String>) -> Self {
+        let mut nodes = vec![TrieNode::default()];
+        for (id, piece) in pieces.iter().enumerate().filter(|(_, piece)| !piece.is_empty()) {
+            let mut node = 0;
+            for c in piece.chars() {
+                node = match nodes[node].children.binary_search_by_key(&c, |&(c, _)| c) {
+                    Ok(i) => nodes[node].children[i].1,
+                    Err(i) => {
+                        nodes.push(TrieNode::default());
+                        let child = nodes.len() - 1;
+                        nodes[node].children.insert(i, (c, child));
+                        child
+                    }
+                };
+            }
+            nodes[node].tokens.push(id as u32);
+        }
+        TokenTrie { pieces, nodes }
+    }
+
+    pub fn piece(&self, id: u32) -> &str {
+        self.pieces.get(id as usize).map_or("", |s| s.as_str())
+    }
+
+    // Below `node`, where the grammar is left with `stacks`; a character the
+    // grammar rejects rules out every token continuing with it.
+    fn visit(&self, grammar: &Grammar, node: usize, stacks: &[Stack], allow: &mut impl FnMut(u32)) {
+        for &(c, child) in &self.nodes[node].children {
+            let stacks = grammar.accept(stacks, c);
+            if stacks.is_empty() {
+                continue;
+            }
+            self.nodes[child].tokens.iter().for_each(|&id| allow(id));
+            self.visit(grammar, child, &stacks, allow);
+        }
+    }
+}
+
+// Masks every token whose text the grammar does not allow next. `vocab` holds
+// the text each token id decodes to; special tokens and byte pieces that are
+// not valid UTF-8 on their own should map to an empty string and are never
+// allowed. End-of-sequence tokens are allowed once the grammar is complete,
+// or when nothing else is, so that generation cannot get stuck.
+pub struct GrammarProcessor {
+    matcher: GrammarMatcher,
+    vocab: Arc<TokenTrie>,
+    eos_token_ids: Vec<u32>,
+    seen: Option<usize>, // length of the history already fed to the matcher
+}
+
+impl GrammarProcessor {
+    pub fn new(grammar: Arc<Grammar>, vocab: Arc<TokenTrie>, eos_token_ids: Vec<u32>) -> Self {
+        GrammarProcessor { matcher: GrammarMatcher::new(grammar), vocab, eos_token_ids, seen: None }
+    }
+}
+
+impl LogitProcessor for GrammarProcessor {
+    fn process(&mut self, logits: &mut [f32], history: &[u32]) {
+        // The first call sees just the prompt, later ones the tokens sampled since
+        let seen = *self.seen.get_or_insert(history.len());
+        for &token_id in &history[seen..] {
+            self.matcher.accept_str(self.vocab.piece(token_id));
+        }
+        self.seen = Some(history.len());
+
+        let mut allowed = vec![false; logits.len()];
+        self.matcher.allowed_tokens(&self.vocab, |id| {
+            if let Some(allowed) = allowed.get_mut(id as usize) {
+                *allowed = true;
+            }
+        });
+        let mut any_allowed = false;
+        for (id, logit) in logits.iter_mut().enumerate() {
+            if self.eos_token_ids.contains(&(id as u32)) {
+                continue;
+            }
+            if allowed[id] {
+                any_allowed = true;
+            } else {
+                *logit = f32::NEG_INFINITY;
+            }
+        }
+        if any_allowed && !self.matcher.is_complete() {
+            for &id in &self.eos_token_ids {
+                if let Some(logit) = logits.get_mut(id as usize) {
+                    *logit = f32::NEG_INFINITY;
+                }
+            }
+        }
+    }
+}
+
+const JSON_PRIMITIVES: &str = r#"
+ws ::= " "?
+string ::= "\"" ( [^"\\\x00-\x1f] | "\\" ( ["\\/bfnrt] | "u" [0-9a-fA-F] [0-9a-fA-F] [0-9a-fA-F] [0-9a-fA-F] ) )* "\""
+number ::= integer ( "." [0-9]+ )? ( [eE] [-+]? [0-9]+ )?
+integer ::= "-"? ( "0" | [1-9] [0-9]* )
+boolean ::= "true" | "false"
+null ::= "null"
+value ::= object | array | string | number | boolean | null
+object ::= "{" ws ( string ws ":" ws value ws ( "," ws string ws ":" ws value ws )* )? "}"
+array ::= "[" ws ( value ws ( "," ws value ws )* )? "]"
+"#;
+
+// Translate a JSON schema into a grammar accepting the JSON it describes.
+// Handled are `type` (also as a list), `properties`, `items`, `enum`,
+// `const`, `anyOf`/`oneOf` and local `$ref`s. Objects list their properties
+// in declaration order, all of them required; other keywords (formats,
+// lengths, bounds, additional properties) are not enforced.
+pub fn json_schema_to_gbnf(schema: &Value) -> Result<String, GrammarError> {
+    let mut converter = SchemaConverter { root: schema, rules: Vec::new(), refs: HashMap::new() };
+    let root = converter.visit(schema, "root")?;
+    let mut gbnf = format!("root ::= {root}\n");
+    for (name, body) in &converter.rules {
+        gbnf += &format!("{name} ::= {body}\n");
+    }
+    gbnf += JSON_PRIMITIVES;
+    Ok(gbnf)
+}
+
+struct SchemaConverter<'a> {
+    root: &'a Value,
+    rules: Vec<(String, String)>,
+    refs: HashMap<String, String>, // $ref -> rule name
+}
+
+impl SchemaConverter<'_> {
+    fn error<R>(&self, message: impl Into<String>) -> Result<R, GrammarError> {
+        Err(GrammarError { pos: 0, message: message.into() })
+    }
+
+    // Reserve a rule name derived from `hint` that no other rule uses.
+    fn name(&self, hint: &str) -> String {
+        let base: String = hint.chars().map(|c| if c.is_ascii_alphanumeric() { c } else { '-' }).collect();
+        let base = format!("schema-{base}");
+        let taken = |name: &String| self.rules.iter().any(|(n, _)| n == name) || self.refs.values().any(|n| n == name);
+        let mut name = base.clone();
+        let mut i = 1;
+        while taken(&name) {
+            name = format!("{base}{i}");
+            i += 1;
+        }
+        name
+    }
+
+    fn define(&mut self, hint: &str, body: String) -> String {
+        let name = self.name(hint);
+        self.rules.push((name.clone(), body));
+        name
+    }
+
+    fn visit(&mut self, schema: &Value, hint: &str) -> Result<String, GrammarError> {
+        let schema = match schema {
+            Value::Bool(true) => return Ok("value".into()),
+            Value::Object(schema) => schema,
+            _ => return self.error(format!("unsupported schema {schema}")),
+        };
+        if let Some(reference) = schema.get("$ref").and_then(Value::as_str) {
+            if let Some(name) = self.refs.get(reference) {
+                return Ok(name.clone());
+            }
+            let Some(target) = reference.strip_prefix('#').and_then(|p| self.root.pointer(p)) else {
+                return self.error(format!("cannot resolve $ref {reference}"));
+            };
+            // Registered before visiting, for recursive schemas
+            let hint = reference.rsplit('/').next().unwrap();
+            let name = self.name(hint);
+            self.refs.insert(reference.to_string(), name.clone());
+            let body = self.visit(target, hint)?;
+            self.rules.push((name.clone(), body));
+            return Ok(name);
+        }
+        if let Some(value) = schema.get("const") {
+            return Ok(literal(&value.to_string()));
+        }
+        if let Some(values) = schema.get("enum").and_then(Value::as_array) {
+            let alternatives: Vec<String> = values.iter().map(|v| literal(&v.to_string())).collect();
+            return Ok(format!("( {} )", alternatives.join(" | ")));
+        }
+        if let Some(schemas) = schema.get("anyOf").or_else(|| schema.get("oneOf")).and_then(Value::as_array) {
+            let mut alternatives = Vec::new();
+            for (i, schema) in schemas.iter().enumerate() {
+                alternatives.push(self.visit(schema, &format!("{hint}-{i}"))?);
+            }
+            return Ok(format!("( {} )", alternatives.join(" | ")));
+        }
+        let types: Vec<&str> = match schema.get("type") {
+            Some(Value::String(t)) => vec![t.as_str()],
+            Some(Value::Array(ts)) => ts.iter().filter_map(Value::as_str).collect(),
+            None if schema.contains_key("properties") => vec!["object"],
+            None if schema.contains_key("items") => vec!["array"],
+            None => return Ok("value".into()),
+            Some(t) => return self.error(format!("unsupported type {t}")),
+        };
+        let mut alternatives = Vec::new();
+        for t in types {
+            alternatives.push(match t {
+                "string" | "number" | "integer" | "boolean" | "null" => t.to_string(),
+                "object" => match schema.get("properties").and_then(Value::as_object) {
+                    Some(properties) if !properties.is_empty() => {
+                        let mut members = Vec::new();
+                        for (key, property) in properties {
+                            let value = self.visit(property, &format!("{hint}-{key}"))?;
+                            members.push(format!("{} ws \":\" ws {value} ws", literal(&Value::from(key.as_str()).to_string())));
+                        }
+                        let body = format!("\"{{\" ws {} \"}}\"", members.join(" \",\" ws "));
+                        self.define(hint, body)
+                    }
+                    _ => "object".into(),
+                },
+                "array" => match schema.get("items") {
+                    Some(items) => {
+                        let item = self.visit(items, &format!("{hint}-item"))?;
+                        let body = format!("\"[\" ws ( {item} ws ( \",\" ws {item} ws )* )? \"]\"");
+                        self.define(hint, body)
+                    }
+                    None => "array".into(),
+                },
+                _ => return self.error(format!("unsupported type \"{t}\"")),
+            });
+        }
+        Ok(match alternatives.len() {
+            1 => alternatives.pop().unwrap(),
+            _ => format!("( {} )", alternatives.join(" | ")),
+        })
+    }
+}
+
+// A grammar literal matching `text` exactly.
+fn literal(text: &str) -> String {
+    let mut out = String::from("\"");
+    for c in text.chars() {
+        match c {
+            '"' => out += "\\\"",
+            '\\' => out += "\\\\",
+            '\n' => out += "\\n",
+            '\r' => out += "\\r",
+            '\t' => out += "\\t",
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+#[test]
+fn test_grammar_match() {
+    let grammar = Arc::new(
+        Grammar::parse(
+            r#"
+            root ::= answer | list   # a comment
+            answer ::= "yes" | "no"
+            list ::= "[" ( item ( "," item )* )? "]"
+            item ::= [0-9]+ | "\"" [^"]* "\""
+            "#,
+        )
+        .unwrap(),
+    );
+    let matches = |text: &str| {
+        let mut matcher = GrammarMatcher::new(grammar.clone());
+        matcher.accept_str(text) && matcher.is_complete()
+    };
+    assert!(matches("yes"));
+    assert!(matches("[]"));
+    assert!(matches("[1,23,\"a,b\"]"));
+    assert!(!matches("ye"));
+    assert!(!matches("[1,]"));
+    assert!(!matches("[\"a\"\"]"));
+
+    let mut matcher = GrammarMatcher::new(grammar.clone());
+    assert!(matcher.allows("[1"));
+    assert!(!matcher.allows("maybe"));
+    assert!(matcher.accept_str("[12"));
+    assert!(!matcher.is_complete());
+    assert!(matcher.allows("3,\""));
+    assert!(!matcher.allows("a"));
+}
+
+#[test]
+fn test_grammar_errors() {
+    let error = |src: &str| Grammar::parse(src).unwrap_err().message;
+    assert_eq!(error("root ::= a\n"), "undefined rule 'a'");
+    assert_eq!(error("a ::= \"x\"\n"), "missing 'root' rule");
+    assert_eq!(error("root ::= root \"x\" | \"y\"\n"), "left recursion is not supported");
+    assert_eq!(error("root ::= a\na ::= \"x\"? root\n"), "left recursion is not supported");
+    assert_eq!(error("root := \"x\"\n"), "expected '::='");
+    assert!(Grammar::parse("root ::= \"x\" |\n  \"y\" root\n").is_ok());
+}
+
+#[test]
+fn test_json_schema_grammar() {
+    let schema = serde_json::json!({
+        "type": "object",
+        "properties": {
+            "name": { "type": "string" },
+            "unit": { "enum": ["celsius", "fahrenheit"] },
+            "days": { "type": "array", "items": { "type": "integer" } },
+            "place": { "$ref": "#/$defs/place" }
+        },
+        "$defs": {
+            "place": { "type": ["object", "null"], "properties": { "city": { "type": "string" } } }
+        }
+    });
+    let grammar = Arc::new(Grammar::parse(&json_schema_to_gbnf(&schema).unwrap()).unwrap());
+    let matches = |text: &str| {
+        let mut matcher = GrammarMatcher::new(grammar.clone());
+        matcher.accept_str(text) && matcher.is_complete()
+    };
+    assert!(matches(r#"{"name":"Paris","unit":"celsius","days":[1,2],"place":null}"#));
+    assert!(matches(r#"{ "name": "a\"b", "unit": "fahrenheit", "days": [], "place": {"city": "x"} }"#));
+    assert!(!matches(r#"{"name":"Paris","unit":"kelvin","days":[],"place":null}"#));
+    assert!(!matches(r#"{"name":"Paris","unit":"celsius","days":[1.5],"place":null}"#));
+    assert!(!matches(r#"{"unit":"celsius","name":"Paris","days":[],"place":null}"#));
+}
+
+#[test]
+fn test_grammar_processor() {
+    let grammar = Arc::new(Grammar::parse("root ::= \"ab\" | \"a\" \"c\"+\n").unwrap());
+    let vocab: Vec<String> = ["<eos>", "a", "b", "ab", "c", "cc", "x", ""].iter().map(|s| s.to_string()).collect();
+    let mut processor = GrammarProcessor::new(grammar, Arc::new(TokenTrie::new(vocab)), vec![0]);
+    let allowed = |processor: &mut GrammarProcessor, history: &[u32]| {
+        let mut logits = vec![0.; 8];
+        processor.process(&mut logits, history);
+        logits.iter().enumerate().filter(|(_, l)| l.is_finite()).map(|(i, _)| i).collect::<Vec<_>>()
+    };
+    assert_eq!(allowed(&mut processor, &[9, 9]), [1, 3]);
+    assert_eq!(allowed(&mut processor, &[9, 9, 1]), [2, 4, 5]);
+    assert_eq!(allowed(&mut processor, &[9, 9, 1, 4]), [0, 4, 5]);
+}
+
+#[test]
+fn test_token_trie() {
+    let grammar = Arc::new(Grammar::parse(&json_schema_to_gbnf(&serde_json::json!({"type": "array", "items": {"type": "integer"}})).unwrap()).unwrap());
+    let pieces: Vec<String> = ["[", "[1", "[12", "1", "12", ",", ", ", "]", "a", "", "[]", "1]", "é"].iter().map(|s| s.to_string()).collect();
+    let vocab = TokenTrie::new(pieces.clone());
+    let mut matcher = GrammarMatcher::new(grammar);
+    // Matching through the trie allows exactly the tokens matching one at a time does
+    for text in ["", "[", "1", ","] {
+        assert!(matcher.accept_str(text));
+        let mut allowed = Vec::new();
+        matcher.allowed_tokens(&vocab, |id| allowed.push(id));
+        allowed.sort_unstable();
+        let expected = (0..pieces.len() as u32).filter(|&id| !pieces[id as usize].is_empty() && matcher.allows(vocab.piece(id))).collect::<Vec<_>>();
+        assert_eq!(allowed, expected);
+    }
+}
@@ -1,43 +1,423 @@
-use std::{usize, vec};
-
+use std::fs::File;
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::path::Path;
+use clap::ValueEnum;
+use half::bf16;
+use serde::{Deserialize, Serialize};
+use crate::operators::ToF32;
 use crate::tensor::Tensor;
+
+// Element type stored in the cache. Each head's slice of a k/v row is encoded
+// on its own and yields the scale it has to be multiplied by when read back.
+pub trait KVElem: Copy + Default + ToF32 {
+    const DTYPE: CacheDtype;
+    fn encode(src: &[f32], dst: &mut [Self]) -> f32;
+    fn write_le_bytes(&self, out: &mut Vec<u8>);
+    fn read_le_bytes(bytes: &[u8]) -> Self;
+}
+
+impl KVElem for f32 {
+    const DTYPE: CacheDtype = CacheDtype::F32;
+    fn encode(src: &[f32], dst: &mut [Self]) -> f32 {
+        dst.copy_from_slice(src);
+        1.
+    }
+    fn write_le_bytes(&self, out: &mut Vec<u8>) {
+        out.extend_from_slice(&self.to_le_bytes());
+    }
+    fn read_le_bytes(bytes: &[u8]) -> Self {
+        Self::from_le_bytes(bytes.try_into().unwrap())
+    }
+}
+
+impl KVElem for bf16 {
+    const DTYPE: CacheDtype = CacheDtype::BF16;
+    fn encode(src: &[f32], dst: &mut [Self]) -> f32 {
+        dst.iter_mut().zip(src).for_each(|(y, x)| *y = bf16::from_f32(*x));
+        1.
+    }
+    fn write_le_bytes(&self, out: &mut Vec<u8>) {
+        out.extend_from_slice(&self.to_le_bytes());
+    }
+    fn read_le_bytes(bytes: &[u8]) -> Self {
+        Self::from_le_bytes(bytes.try_into().unwrap())
+    }
+}
+
+// Symmetric absmax quantization
+impl KVElem for i8 {
+    const DTYPE: CacheDtype = CacheDtype::Int8;
+    fn encode(src: &[f32], dst: &mut [Self]) -> f32 {
+        let absmax = src.iter().fold(0f32, |a, x| a.max(x.abs()));
+        let scale = if absmax > 0. { absmax / 127. } else { 1. };
+        dst.iter_mut().zip(src).for_each(|(y, x)| *y = (x / scale).round().clamp(-127., 127.) as i8);
+        scale
+    }
+    fn write_le_bytes(&self, out: &mut Vec<u8>) {
+        out.extend_from_slice(&self.to_le_bytes());
+    }
+    fn read_le_bytes(bytes: &[u8]) -> Self {
+        Self::from_le_bytes(bytes.try_into().unwrap())
+    }
+}
+
+#[derive(Serialize, Deserialize, ValueEnum, Debug, Clone, Copy, Default, PartialEq)]
+#[serde(rename_all = "lowercase")]
+#[repr(u8)]
+pub enum CacheDtype {
+    #[default]
+    F32,
+    BF16,
+    Int8,
+}
+
 pub struct KVCache<T> {
     k_cache: Vec<Tensor<T>>, // (max_seq_len, n_kv_head * dqkv) x layers
     v_cache: Vec<Tensor<T>>, // (max_seq_len, n_kv_head * dqkv) x layers
-    #[allow(unused)]
+    k_scale: Vec<Tensor<f32>>, // (max_seq_len, n_kv_head) x layers
+    v_scale: Vec<Tensor<f32>>, // (max_seq_len, n_kv_head) x layers
     max_seq_len: usize,
+    n_heads: usize,
     dim: usize,
     length: usize, // length of the current sequence
 }
 
-impl<T: Default + Copy> KVCache<T> {
-    pub fn new(n_layers: usize, max_seq_len: usize, dim: usize, init_len: usize) -> Self {
+impl<T: KVElem> KVCache<T> {
+    pub fn new(n_layers: usize, max_seq_len: usize, n_heads: usize, head_dim: usize, init_len: usize) -> Self {
+        let dim = n_heads * head_dim;
         KVCache {
             k_cache: (0..n_layers)
-                .map(|_| Tensor::default(&vec![max_seq_len, dim]))
+                .map(|_| Tensor::default(&[max_seq_len, dim]))
                 .collect(),
             v_cache: (0..n_layers)
-                .map(|_| Tensor::default(&vec![max_seq_len, dim]))
+                .map(|_| Tensor::default(&[max_seq_len, dim]))
+                .collect(),
+            k_scale: (0..n_layers)
+                .map(|_| Tensor::new(vec![1.; max_seq_len * n_heads], &[max_seq_len, n_heads]))
                 .collect(),
-            max_seq_len: max_seq_len,
-            dim: dim,
+            v_scale: (0..n_layers)
+                .map(|_| Tensor::new(vec![1.; max_seq_len * n_heads], &[max_seq_len, n_heads]))
+                .collect(),
+            max_seq_len,
+            n_heads,
+            dim,
             length: init_len,
         }
     }
 
+    // Memory one cached position takes across all layers, values and scales.
+    pub fn bytes_per_token(n_layers: usize, n_heads: usize, head_dim: usize) -> usize {
+        n_layers * 2 * (n_heads * head_dim * size_of::<T>() + n_heads * size_of::<f32>())
+    }
+
+    // Memory held by the whole cache, used or not.
+    pub fn bytes(&self) -> usize {
+        Self::bytes_per_token(self.k_cache.len(), self.n_heads, self.dim / self.n_heads) * self.max_seq_len
+    }
+
     pub fn k_cache(&mut self, layer: usize, start: usize) -> Tensor<T> {
-        self.k_cache[layer].slice(start * self.dim, &vec![self.length - start, self.dim])
+        self.k_cache[layer].slice(start * self.dim, &[self.length - start, self.dim])
     }
 
     pub fn v_cache(&mut self, layer: usize, start: usize) -> Tensor<T> {
-        self.v_cache[layer].slice(start * self.dim, &vec![self.length - start, self.dim])
+        self.v_cache[layer].slice(start * self.dim, &[self.length - start, self.dim])
+    }
+
+    pub fn k_scale(&mut self, layer: usize, start: usize) -> Tensor<f32> {
+        self.k_scale[layer].slice(start * self.n_heads, &[self.length - start, self.n_heads])
     }
 
+    pub fn v_scale(&mut self, layer: usize, start: usize) -> Tensor<f32> {
+        self.v_scale[layer].slice(start * self.n_heads, &[self.length - start, self.n_heads])
+    }
+
+    // Encode `k` (seq, n_kv_head * dqkv) into the positions starting at `start`.
+    pub fn store_k(&mut self, layer: usize, start: usize, k: &Tensor<f32>) {
+        let (mut dst, mut scale) = (self.k_cache(layer, start), self.k_scale(layer, start));
+        encode_rows(k, &mut dst, &mut scale);
+    }
+
+    pub fn store_v(&mut self, layer: usize, start: usize, v: &Tensor<f32>) {
+        let (mut dst, mut scale) = (self.v_cache(layer, start), self.v_scale(layer, start));
+        encode_rows(v, &mut dst, &mut scale);
+    }
+
+    // Dequantized copy of the keys from `start` on, (seq - start, n_kv_head * dqkv).
+    pub fn load_k(&mut self, layer: usize, start: usize) -> Tensor<f32> {
+        let (src, scale) = (self.k_cache(layer, start), self.k_scale(layer, start));
+        let head_dim = self.dim / self.n_heads;
+        let data = src.data().iter().enumerate()
+            .map(|(i, x)| x.to_f32() * scale.data()[i / head_dim])
+            .collect();
+        Tensor::new(data, src.shape())
+    }
+
+    // Grow the sequence by `seq_len` positions that are about to be written.
+    // Storage still shared with a fork is copied first, in full, so writes stay private.
     pub fn increment(&mut self, seq_len: usize){
+        self.k_cache.iter_mut().for_each(Tensor::make_unique);
+        self.v_cache.iter_mut().for_each(Tensor::make_unique);
+        self.k_scale.iter_mut().for_each(Tensor::make_unique);
+        self.v_scale.iter_mut().for_each(Tensor::make_unique);
         self.length += seq_len;
     }
 
+    // Roll back to the first `len` positions, e.g. to regenerate the last turn.
+    pub fn truncate(&mut self, len: usize) {
+        assert!(len <= self.length, "cannot truncate cache of length {} to {len}", self.length);
+        self.length = len;
+    }
+
+    // Drop `n` positions starting at `start`, moving the later ones down.
+    pub fn discard(&mut self, start: usize, n: usize) {
+        assert!(start + n <= self.length);
+        let length = self.length;
+        for cache in self.k_cache.iter_mut().chain(self.v_cache.iter_mut()) {
+            shift_rows(cache, self.dim, start, n, length);
+        }
+        for scale in self.k_scale.iter_mut().chain(self.v_scale.iter_mut()) {
+            shift_rows(scale, self.n_heads, start, n, length);
+        }
+        self.length -= n;
+    }
+
+    #[allow(unused)]
+    pub fn reset(&mut self) {
+        self.length = 0;
+    }
+
+    // Branch off a cache sharing the current state. Forking itself copies
+    // nothing, but the first write on either side afterwards copies that side's
+    // whole buffers, all `max_seq_len` positions of every layer (see `increment`).
+    pub fn fork(&self) -> Self {
+        KVCache {
+            k_cache: self.k_cache.clone(),
+            v_cache: self.v_cache.clone(),
+            k_scale: self.k_scale.clone(),
+            v_scale: self.v_scale.clone(),
+            max_seq_len: self.max_seq_len,
+            n_heads: self.n_heads,
+            dim: self.dim,
+            length: self.length,
+        }
+    }
+
     pub fn len(&self) -> usize {
         self.length
     }
+
+    pub fn max_seq_len(&self) -> usize {
+        self.max_seq_len
+    }
+
+    // Snapshot the cached positions to `path`. `fingerprint` identifies the
+    // model that produced them, see `load`.
+    pub fn save(&self, path: impl AsRef<Path>, fingerprint: u64) -> io::Result<()> {
+        let mut out = Vec::new();
+        out.extend_from_slice(SNAPSHOT_MAGIC);
+        out.push(T::DTYPE as u8);
+        out.extend_from_slice(&fingerprint.to_le_bytes());
+        for n in [self.k_cache.len(), self.max_seq_len, self.n_heads, self.dim, self.length] {
+            out.extend_from_slice(&(n as u64).to_le_bytes());
+        }
+        for layer in 0..self.k_cache.len() {
+            for cache in [&self.k_cache[layer], &self.v_cache[layer]] {
+                cache.data()[..self.length * self.dim].iter().for_each(|x| x.write_le_bytes(&mut out));
+            }
+            for scale in [&self.k_scale[layer], &self.v_scale[layer]] {
+                scale.data()[..self.length * self.n_heads].iter().for_each(|x| x.write_le_bytes(&mut out));
+            }
+        }
+        BufWriter::new(File::create(path)?).write_all(&out)
+    }
+
+    // Restore a snapshot written by `save`, rejecting it if it was taken with
+    // a different element type or by a model with a different fingerprint.
+    pub fn load(path: impl AsRef<Path>, fingerprint: u64) -> io::Result<Self> {
+        let invalid = |msg: String| io::Error::new(io::ErrorKind::InvalidData, msg);
+        let mut bytes = Vec::new();
+        BufReader::new(File::open(path)?).read_to_end(&mut bytes)?;
+        let mut reader = bytes.as_slice();
+        let mut take = |n: usize| -> io::Result<&[u8]> {
+            if reader.len() < n {
+                return Err(io::Error::from(io::ErrorKind::UnexpectedEof));
+            }
+            let (head, tail) = reader.split_at(n);
+            reader = tail;
+            Ok(head)
+        };
+
+        if take(SNAPSHOT_MAGIC.len())? != SNAPSHOT_MAGIC {
+            return Err(invalid("not a KV cache snapshot".to_string()));
+        }
+        let dtype = take(1)?[0];
+        if dtype != T::DTYPE as u8 {
+            return Err(invalid(format!("snapshot holds dtype #{dtype}, expected {:?}", T::DTYPE)));
+        }
+        let saved = u64::from_le_bytes(take(8)?.try_into().unwrap());
+        if saved != fingerprint {
+            return Err(invalid(format!("snapshot was taken by model {saved:016x}, not {fingerprint:016x}")));
+        }
+        let mut dims = [0usize; 5];
+        for n in dims.iter_mut() {
+            *n = u64::from_le_bytes(take(8)?.try_into().unwrap()) as usize;
+        }
+        let [n_layers, max_seq_len, n_heads, dim, length] = dims;
+        if n_heads == 0 || dim % n_heads != 0 || length > max_seq_len {
+            return Err(invalid("corrupted snapshot header".to_string()));
+        }
+
+        let mut cache = Self::new(n_layers, max_seq_len, n_heads, dim / n_heads, length);
+        for layer in 0..n_layers {
+            for t in [&mut cache.k_cache[layer], &mut cache.v_cache[layer]] {
+                read_rows(t, take(length * dim * size_of::<T>())?);
+            }
+            for t in [&mut cache.k_scale[layer], &mut cache.v_scale[layer]] {
+                read_rows(t, take(length * n_heads * size_of::<f32>())?);
+            }
+        }
+        Ok(cache)
+    }
+}
+
+// A cache kept from one generation to the next together with the tokens it
+// holds, so that a prompt extending an earlier one only runs its new tokens.
+pub struct PromptCache<T> {
+    pub cache: Option<KVCache<T>>,
+    pub tokens: Vec<u32>,
+}
+
+impl<T> Default for PromptCache<T> {
+    fn default() -> Self {
+        PromptCache { cache: None, tokens: Vec::new() }
+    }
+}
+
+impl<T: KVElem> PromptCache<T> {
+    // Keep the longest cached prefix of `prompt` and return its length. The
+    // last prompt token always has to run again to give the next logits. A
+    // cache of another length than `max_seq_len` is replaced by `new_cache`.
+    pub fn reuse(&mut self, prompt: &[u32], max_seq_len: usize, new_cache: impl FnOnce() -> KVCache<T>) -> usize {
+        if self.cache.as_ref().is_none_or(|cache| cache.max_seq_len() != max_seq_len) {
+            self.cache = Some(new_cache());
+            self.tokens.clear();
+        }
+        let kept = self.tokens.iter().zip(prompt)
+            .take_while(|(a, b)| a == b)
+            .count()
+            .min(prompt.len().saturating_sub(1));
+        self.cache.as_mut().unwrap().truncate(kept);
+        self.tokens.truncate(kept);
+        kept
+    }
+}
+
+const SNAPSHOT_MAGIC: &[u8] = b"LMKV";
+
+fn read_rows<T: KVElem>(t: &mut Tensor<T>, bytes: &[u8]) {
+    let data = unsafe { t.data_mut() };
+    for (x, chunk) in data.iter_mut().zip(bytes.chunks_exact(size_of::<T>())) {
+        *x = T::read_le_bytes(chunk);
+    }
+}
+
+fn encode_rows<T: KVElem>(src: &Tensor<f32>, dst: &mut Tensor<T>, scale: &mut Tensor<f32>) {
+    let head_dim = dst.shape()[1] / scale.shape()[1];
+    let dst = &mut unsafe { dst.data_mut() }[..src.size()];
+    let scale = unsafe { scale.data_mut() };
+    for (i, (x, y)) in src.data().chunks_exact(head_dim).zip(dst.chunks_exact_mut(head_dim)).enumerate() {
+        scale[i] = T::encode(x, y);
+    }
+}
+
+fn shift_rows<T: Copy + Default>(t: &mut Tensor<T>, row: usize, start: usize, n: usize, length: usize) {
+    t.make_unique();
+    unsafe { t.data_mut() }.copy_within((start + n) * row..length * row, start * row);
+}
+
+#[test]
+fn test_truncate_and_reset() {
+    let mut cache = KVCache::<f32>::new(2, 8, 2, 2, 0);
+    cache.increment(5);
+    assert_eq!(cache.len(), 5);
+    cache.truncate(3);
+    assert_eq!(cache.len(), 3);
+    assert_eq!(cache.k_cache(1, 0).shape(), &vec![3, 4]);
+    cache.reset();
+    assert_eq!(cache.len(), 0);
+}
+
+#[test]
+fn test_discard() {
+    let mut cache = KVCache::<f32>::new(1, 4, 1, 1, 0);
+    cache.increment(4);
+    unsafe { cache.v_cache(0, 0).data_mut() }.copy_from_slice(&[1., 2., 3., 4.]);
+    cache.discard(1, 2);
+    assert_eq!(cache.len(), 2);
+    assert_eq!(cache.v_cache(0, 0).data(), &[1., 4.]);
+}
+
+#[test]
+fn test_fork_copy_on_write() {
+    let mut cache = KVCache::<f32>::new(1, 4, 1, 2, 0);
+    cache.increment(1);
+    unsafe { cache.k_cache(0, 0).data_mut() }.copy_from_slice(&[1., 2.]);
+
+    let mut fork = cache.fork();
+    assert_eq!(fork.len(), 1);
+    assert_eq!(fork.k_cache(0, 0).data(), &[1., 2.]);
+
+    // Both branches append a different token at the same position
+    fork.increment(1);
+    unsafe { fork.k_cache(0, 1).data_mut() }.copy_from_slice(&[5., 6.]);
+    cache.increment(1);
+    unsafe { cache.k_cache(0, 1).data_mut() }.copy_from_slice(&[3., 4.]);
+
+    assert_eq!(cache.k_cache(0, 0).data(), &[1., 2., 3., 4.]);
+    assert_eq!(fork.k_cache(0, 0).data(), &[1., 2., 5., 6.]);
+}
+
+#[test]
+fn test_int8_round_trip() {
+    let mut cache = KVCache::<i8>::new(1, 4, 2, 2, 0);
+    cache.increment(2);
+    let k = Tensor::new(vec![0.5, -1.0, 100., 20., 0., 0., -3., 3.], &[2, 4]);
+    cache.store_k(0, 0, &k);
+    assert_eq!(cache.k_scale(0, 0).data(), &[1. / 127., 100. / 127., 1., 3. / 127.]);
+    assert!(cache.load_k(0, 0).data().iter().zip(k.data()).all(|(a, b)| (a - b).abs() <= 0.4));
+}
+
+#[test]
+fn test_snapshot_round_trip() {
+    let path = std::env::temp_dir().join(format!("kvcache-{}.bin", std::process::id()));
+    let mut cache = KVCache::<i8>::new(2, 8, 2, 2, 0);
+    cache.increment(3);
+    cache.store_v(1, 0, &Tensor::new((0..12).map(|x| x as f32).collect(), &[3, 4]));
+    cache.save(&path, 42).unwrap();
+
+    let mut restored = KVCache::<i8>::load(&path, 42).unwrap();
+    assert_eq!(restored.len(), 3);
+    assert_eq!(restored.max_seq_len(), 8);
+    assert_eq!(restored.v_cache(1, 0).data(), cache.v_cache(1, 0).data());
+    assert_eq!(restored.v_scale(1, 0).data(), cache.v_scale(1, 0).data());
+
+    assert!(KVCache::<i8>::load(&path, 43).is_err());
+    assert!(KVCache::<f32>::load(&path, 42).is_err());
+    std::fs::remove_file(path).unwrap();
+}
+
+#[test]
+fn test_prompt_cache_reuse() {
+    let new_cache = || KVCache::<f32>::new(2, 16, 2, 4, 0);
+    let mut cached = PromptCache::default();
+    assert_eq!(cached.reuse(&[1, 2, 3], 16, new_cache), 0);
+    cached.cache.as_mut().unwrap().increment(5);
+    cached.tokens.extend([1, 2, 3, 4, 5]);
+    // Only the shared prefix stays, and never the whole prompt
+    assert_eq!(cached.reuse(&[1, 2, 3, 9], 16, new_cache), 3);
+    assert_eq!(cached.cache.as_ref().unwrap().len(), 3);
+    assert_eq!(cached.reuse(&[1, 2, 3], 16, new_cache), 2);
+    assert_eq!(cached.tokens, [1, 2]);
+    assert_eq!(cached.reuse(&[1, 2, 3], 8, || KVCache::new(2, 8, 2, 4, 0)), 0);
+    assert_eq!(cached.cache.as_ref().unwrap().max_seq_len(), 8);
 }
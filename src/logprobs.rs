@@ -0,0 +1,38 @@
+use crate::operators as OP;
+
+// How likely the model found an emitted token, with the `top_n` most likely
+// alternatives at that position. Log-probabilities come from the raw logits,
+// before penalties, temperature or truncation are applied by the sampler.
+#[derive(Debug, Clone, PartialEq)]
+pub struct TokenLogprob {
+    pub token_id: u32,
+    pub logprob: f32,
+    pub top: Vec<(u32, f32)>,
+}
+
+impl TokenLogprob {
+    pub fn new(logits: &[f32], token_id: u32, top_n: usize) -> Self {
+        let logprobs = OP::log_softmax(logits);
+        let mut top: Vec<(u32, f32)> = logprobs.iter().enumerate().map(|(i, &p)| (i as u32, p)).collect();
+        if top_n < top.len() {
+            top.select_nth_unstable_by(top_n, |a, b| b.1.total_cmp(&a.1));
+            top.truncate(top_n);
+        }
+        top.sort_by(|a, b| b.1.total_cmp(&a.1));
+        Self { token_id, logprob: logprobs[token_id as usize], top }
+    }
+}
+
+#[test]
+fn test_token_logprob() {
+    use crate::tensor::float_eq;
+    let lp = TokenLogprob::new(&[1., 3., 2., 0.], 2, 2);
+    assert_eq!(lp.token_id, 2);
+    assert!(float_eq(&lp.logprob, &-1.4401897, 1e-5));
+    assert_eq!(lp.top.iter().map(|(id, _)| *id).collect::<Vec<_>>(), [1, 2]);
+    assert!(float_eq(&lp.top[0].1, &-0.4401897, 1e-5));
+
+    // Asking for more alternatives than the vocabulary has returns all of it
+    assert_eq!(TokenLogprob::new(&[1., 3., 2., 0.], 0, 10).top.len(), 4);
+    assert!(TokenLogprob::new(&[1., 3., 2., 0.], 0, 0).top.is_empty());
+}
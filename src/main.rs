@@ -1,119 +1,1070 @@
+mod auth;
+mod beam;
 mod config;
+mod connection;
+mod eval;
+mod grammar;
 mod kvcache;
+mod logprobs;
+mod metrics;
 mod model;
 mod operators;
 mod params;
+mod pool;
+mod registry;
+mod sampler;
+mod session;
+mod settings;
+mod speculative;
+mod stop;
+mod stream;
+mod template;
 mod tensor;
 
-use std::fs::File;
-use std::sync::Arc;
-use dashmap::DashMap;
-use std::{f32, path::PathBuf};
+use std::error::Error;
+use std::convert::Infallible;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use std::f32;
 use half::bf16;
 use operators::ToF32;
 use params::Load;
 use serde::{Deserialize, Serialize};
-use crate::config::LlamaConfigJson;
+use crate::auth::{Auth, AuthError, KeyState};
+use crate::beam::{beam_search, BeamParams};
+use crate::connection::PeerSocket;
+use crate::grammar::{json_schema_to_gbnf, Grammar, GrammarProcessor};
+use crate::kvcache::{CacheDtype, PromptCache};
+use crate::metrics::METRICS;
+use crate::model::{ContextOverflow, Generation};
+use crate::sampler::{Sampler, SamplingParams};
+use crate::stop::{CancelOnDrop, CancelToken, FinishReason, StopCriteria};
+use crate::stream::{done_event, error_event, text_event, ws_done, ws_error, ws_text, TextStream};
+use crate::pool::{ComputePool, PoolError};
+use crate::registry::{LoadedModel, ModelError, ModelRegistry, Weights};
+use crate::session::{open_store, Session, SessionMessage, SessionStore};
+use crate::settings::{Cli, Command, Settings};
+use crate::speculative::DraftStats;
+use crate::template::Message;
 
-use tokenizers::Tokenizer;
-use actix_web::{get, post, App, web, HttpResponse, HttpServer, Responder};
+use clap::Parser;
+use tokio::sync::mpsc;
+use tokio_stream::wrappers::UnboundedReceiverStream;
+use tokio_stream::StreamExt;
+use actix_web::dev::{Service, ServiceRequest};
+use actix_web::rt;
+use actix_web::http::header;
+use actix_web::http::StatusCode;
+use actix_web::{delete, get, post, App, web, HttpMessage, HttpRequest, HttpResponse, HttpServer, Responder};
+use actix_ws::AggregatedMessage;
 
 #[derive(Serialize,Deserialize,Debug)]
 struct Request {
+    #[serde(default)]
     session_id: String,
-    history: String,
+    // Owner of the session, for the per-user session cap
+    #[serde(default)]
+    user_id: Option<String>,
+    // Earlier turns of the session, filled in from the server's store
+    #[serde(default, skip_deserializing)]
+    history: Vec<Message>,
+    #[serde(default)]
     system_message: String,
+    #[serde(default)]
     user_message: String,
+    #[serde(default)]
+    overflow: ContextOverflow,
+    #[serde(default)]
+    kv_cache_dtype: CacheDtype,
+    #[serde(flatten)]
+    sampling: SamplingParams,
+    // Stop strings, matched on the decoded reply
+    #[serde(default)]
+    stop: Vec<String>,
+    // Stop tokens besides the model's end-of-sequence ones
+    #[serde(default)]
+    stop_token_ids: Vec<u32>,
+    // Number of top alternatives to report with each token's log-probability;
+    // when set, the reply is a JSON body instead of plain text
+    #[serde(default)]
+    logprobs: Option<usize>,
+    // Constrain the reply to a GBNF grammar, or to JSON matching a schema
+    #[serde(default)]
+    grammar: Option<String>,
+    #[serde(default)]
+    json_schema: Option<serde_json::Value>,
+    // Send the reply as server-sent events while it is generated
+    #[serde(default)]
+    stream: bool,
+    // Give up after this long, returning what was generated so far; the
+    // server's own limit applies if it is shorter
+    #[serde(default)]
+    timeout_secs: Option<u64>,
+    // Decode with beam search over this many beams instead of sampling; the
+    // reply is a JSON body listing the best `n_best` of them
+    #[serde(default)]
+    beam_width: Option<usize>,
+    #[serde(flatten)]
+    beam: BeamParams,
+    // Speculate with the draft model, this many tokens at a time; the reply
+    // reports how many of them the chat model accepted
+    #[serde(default)]
+    draft_tokens: Option<usize>,
+}
+
+#[derive(Serialize,Debug)]
+struct TokenInfo {
+    token: String,
+    id: u32,
+    logprob: f32,
+}
+
+#[derive(Serialize,Debug)]
+struct LogprobEntry {
+    #[serde(flatten)]
+    token: TokenInfo,
+    top_logprobs: Vec<TokenInfo>,
+}
+
+#[derive(Serialize,Debug)]
+struct BeamEntry {
+    text: String,
+    logprob: f32,
+    score: f32,
+    finish_reason: FinishReason,
+}
+
+#[derive(Serialize,Debug)]
+struct JsonReply<'a> {
+    text: &'a str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    logprobs: Option<&'a [LogprobEntry]>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    beams: Option<&'a [BeamEntry]>,
+}
+
+// Output of a generation: the text goes in the body, the rest in headers.
+struct Reply {
+    text: String,
+    seed: u64,
+    finish_reason: FinishReason,
+    logprobs: Option<Vec<LogprobEntry>>,
+    beams: Option<Vec<BeamEntry>>,
+    draft: Option<DraftStats>,
+    user_token_ids: Vec<u32>,
+    token_ids: Vec<u32>,
+}
+
+// The chat model, and the draft model if the request speculates with one.
+struct ChatModels {
+    chat: Arc<LoadedModel>,
+    draft: Option<Arc<LoadedModel>>,
+}
+
+impl ChatModels {
+    // Without a draft model configured, speculating requests fail in `chat_func`.
+    fn get(models: &ModelRegistry, prompt: &Request) -> Result<Self, ModelError> {
+        let chat_model = models.get("chat")?;
+        let draft = match prompt.draft_tokens {
+            Some(_) => match models.get("draft") {
+                Ok(draft) => Some(draft),
+                Err(ModelError::NotFound(_)) => None,
+                Err(e) => return Err(e),
+            },
+            None => None,
+        };
+        Ok(ChatModels { chat: chat_model, draft })
+    }
+}
+
+// The KV cache a WebSocket chat keeps across its messages, in the element
+// type the last message asked for.
+enum ChatCache {
+    F32(PromptCache<f32>),
+    BF16(PromptCache<bf16>),
+    Int8(PromptCache<i8>),
+}
+
+impl ChatCache {
+    fn new(dtype: CacheDtype) -> Self {
+        match dtype {
+            CacheDtype::F32 => ChatCache::F32(PromptCache::default()),
+            CacheDtype::BF16 => ChatCache::BF16(PromptCache::default()),
+            CacheDtype::Int8 => ChatCache::Int8(PromptCache::default()),
+        }
+    }
+
+    fn dtype(&self) -> CacheDtype {
+        match self {
+            ChatCache::F32(_) => CacheDtype::F32,
+            ChatCache::BF16(_) => CacheDtype::BF16,
+            ChatCache::Int8(_) => CacheDtype::Int8,
+        }
+    }
 }
 
 #[get("/story")]
-async fn story() -> impl Responder {
-    let project_dir = env!("CARGO_MANIFEST_DIR");
-    let model_dir = PathBuf::from(project_dir).join("models").join("story");
-    let llama = model::Llama::<f32>::from_safetensors(&model_dir);
-    let tokenizer = Tokenizer::from_file(model_dir.join("tokenizer.json")).unwrap();
-    let input = "Once upon a time";
-    let binding = tokenizer.encode(input, true).unwrap();
-    let input_ids = binding.get_ids();
-    let output_ids = llama.generate(
-        input_ids,
-        200,
-        0.8,
-        30,
-        0.6,
-    );
-    let mut ans = tokenizer.decode(&output_ids, true).unwrap();
-    ans.insert_str(0,input);
-    HttpResponse::Ok().body(ans)
-}
-
-fn chat_func<T>(model_dir: PathBuf,prompt: &Request) -> String 
+async fn story(request: HttpRequest, models: web::Data<ModelRegistry>, pool: web::Data<ComputePool>, settings: web::Data<Settings>) -> impl Responder {
+    let model = match models.get("story") {
+        Ok(model) => model,
+        Err(e) => return model_error_response(e),
+    };
+    let cancel = cancel_token(&settings, None);
+    let _cancel_on_drop = CancelOnDrop(cancel.clone());
+    if let Some(peer) = request.conn_data::<PeerSocket>() {
+        peer.clone().watch(cancel.clone());
+    }
+    let max_len = settings.generation.story_max_tokens;
+    let key = api_key(&request);
+    let output = pool.submit(move || {
+        let tokenizer = &model.tokenizer;
+        let input = "Once upon a time";
+        let binding = tokenizer.encode(input, true).unwrap();
+        let input_ids = binding.get_ids();
+        let sampler = &mut Sampler::new(0.8, 30, 0.6);
+        let stop = &StopCriteria::none().with_cancel(cancel);
+        let output = match &model.weights {
+            Weights::F32(llama) => llama.generate::<f32>(input_ids, max_len, sampler, ContextOverflow::TruncateLeft, stop, None),
+            Weights::BF16(llama) => llama.generate::<f32>(input_ids, max_len, sampler, ContextOverflow::TruncateLeft, stop, None),
+        };
+        if let (Some(key), Ok(output)) = (&key, &output) {
+            key.record_tokens(output.tokens.len());
+        }
+        output.map(|output| {
+            let mut ans = tokenizer.decode(&output.tokens, true).unwrap();
+            ans.insert_str(0,input);
+            (ans, sampler.seed(), output.finish_reason)
+        }).map_err(|e| e.to_string())
+    });
+    let output = match output {
+        Ok(output) => output.await,
+        Err(e) => return pool_error_response(e),
+    };
+    match output {
+        Ok(Ok((ans, seed, finish_reason))) => HttpResponse::Ok()
+            .insert_header(("X-Seed", seed))
+            .insert_header(("X-Finish-Reason", finish_reason.to_string()))
+            .body(ans),
+        Ok(Err(e)) => HttpResponse::BadRequest().body(e),
+        Err(e) => pool_error_response(e),
+    }
+}
+
+// Generate a reply; `on_text` receives it piece by piece as it is generated.
+// With a `cache`, the part of the prompt it holds from before is not run again.
+fn chat_func<T>(llama: &model::Llama<T>, models: &ChatModels, prompt: &Request, settings: &Settings, cancel: CancelToken, on_text: Option<&dyn Fn(&str)>, cache: Option<&mut ChatCache>) -> Result<Reply, Box<dyn Error>>
 where T: Default + Copy +Load + ToF32
 {
-    let llama = model::Llama::<T>::from_safetensors(&model_dir);
-    let tokenizer = Tokenizer::from_file(model_dir.join("tokenizer.json")).unwrap();
-    let input = format!("{0}<|im_start|>system\n{1}<|im_end|>\n<|im_start|>user\n{2}<|im_end|>\n<|im_start|>assistant",
-                        prompt.history,prompt.system_message,prompt.user_message);
-    println!("{}\n",(|| "-".repeat(50))());
-    // println!("{}\n{}",(|| "-".repeat(50))(),&input);
-    let binding = tokenizer.encode(input, true).unwrap();
-    let input_ids = binding.get_ids();
-    let output_ids = llama.generate(
-        input_ids,
-        100,
-        0.8,
-        30,
-        1.,
-    );
-    tokenizer.decode(&output_ids, true).unwrap()
+    let model = &*models.chat;
+    let (tokenizer, template) = (&model.tokenizer, &model.template);
+    // The weights are shared, so only this request's copy has its context cut
+    let mut llama = llama.clone();
+    if let Some(mb) = settings.compute.kv_cache_mb {
+        let bytes = mb << 20;
+        match prompt.kv_cache_dtype {
+            CacheDtype::F32 => llama.limit_cache::<f32>(bytes),
+            CacheDtype::BF16 => llama.limit_cache::<bf16>(bytes),
+            CacheDtype::Int8 => llama.limit_cache::<i8>(bytes),
+        }
+    }
+    let mut messages = Vec::new();
+    if !prompt.system_message.is_empty() {
+        messages.push(Message::new("system", prompt.system_message.as_str()));
+    }
+    messages.extend(prompt.history.iter().cloned());
+    messages.push(Message::new("user", prompt.user_message.as_str()));
+    let input = template.render(&messages, true)?;
+    // The template adds whatever special tokens the model expects
+    let input_ids = &model.encode(&input, false)?;
+    if let Some(width) = prompt.beam_width {
+        if prompt.beam.n_best == 0 || prompt.beam.n_best > width {
+            return Err("beam_width must be at least n_best, which must be at least 1".into());
+        }
+        if on_text.is_some() || prompt.grammar.is_some() || prompt.json_schema.is_some() || prompt.logprobs.is_some() {
+            return Err("beam search cannot stream, follow a grammar or report logprobs".into());
+        }
+    }
+    let draft = match prompt.draft_tokens {
+        Some(k) => {
+            let draft = models.draft.as_deref().ok_or("speculative decoding needs a draft model, see --draft-model")?;
+            if k == 0 {
+                return Err("draft_tokens must be at least 1".into());
+            }
+            if draft.config.vocab_size != model.config.vocab_size {
+                return Err("the draft model does not share the chat model's vocabulary".into());
+            }
+            if prompt.beam_width.is_some() || prompt.grammar.is_some() || prompt.json_schema.is_some() || prompt.logprobs.is_some() || prompt.sampling.mirostat != 0 {
+                return Err("speculative decoding cannot search beams, follow a grammar, report logprobs or use mirostat".into());
+            }
+            Some((draft, k))
+        }
+        None => None,
+    };
+    let mut sampler = prompt.sampling.sampler();
+    // Turns end with the template's eos token, even if the model config does not say so
+    let mut stop_token_ids = prompt.stop_token_ids.clone();
+    stop_token_ids.extend(tokenizer.token_to_id(template.eos_token()));
+    let grammar = match (&prompt.grammar, &prompt.json_schema) {
+        (Some(gbnf), _) => Some(Grammar::parse(gbnf)?),
+        (None, Some(schema)) => Some(Grammar::parse(&json_schema_to_gbnf(schema)?)?),
+        (None, None) => None,
+    };
+    if let Some(grammar) = grammar {
+        let mut end_ids = llama.eos_token_ids().to_vec();
+        end_ids.extend(&stop_token_ids);
+        sampler = sampler.with(GrammarProcessor::new(Arc::new(grammar), model.vocab.clone(), end_ids));
+    }
+    let sampler = &mut sampler;
+    let decode = |ids: &[u32]| tokenizer.decode(ids, true).unwrap();
+    let text_stream = on_text.map(|send| TextStream::new(&prompt.stop, decode, send));
+    let mut stop = StopCriteria::new(stop_token_ids, prompt.stop.clone(), decode).with_cancel(cancel);
+    if let Some(text_stream) = &text_stream {
+        stop = stop.with_on_token(|id| text_stream.push(id));
+    }
+    let stop = &stop;
+    let max_tokens = settings.generation.max_tokens;
+    let mut fresh_cache = ChatCache::new(prompt.kv_cache_dtype);
+    let cache = cache.unwrap_or(&mut fresh_cache);
+    if cache.dtype() != prompt.kv_cache_dtype {
+        *cache = ChatCache::new(prompt.kv_cache_dtype);
+    }
+    let mut speculation = None;
+    let (output, beams) = match (prompt.beam_width, draft) {
+        (Some(width), _) => {
+            let hypotheses = match prompt.kv_cache_dtype {
+                CacheDtype::F32 => beam_search::<T, f32>(&llama, input_ids, width, max_tokens, &prompt.beam, stop),
+                CacheDtype::BF16 => beam_search::<T, bf16>(&llama, input_ids, width, max_tokens, &prompt.beam, stop),
+                CacheDtype::Int8 => beam_search::<T, i8>(&llama, input_ids, width, max_tokens, &prompt.beam, stop),
+            }?;
+            let beams = hypotheses.iter().map(|h| {
+                let mut text = tokenizer.decode(&h.tokens, true).unwrap();
+                stop.trim(&mut text, h.finish_reason);
+                BeamEntry { text, logprob: h.logprob, score: h.score, finish_reason: h.finish_reason }
+            }).collect();
+            let best = &hypotheses[0];
+            (Generation { tokens: best.tokens.clone(), finish_reason: best.finish_reason, logprobs: Vec::new() }, Some(beams))
+        }
+        (None, Some((draft, k))) => {
+            let output = match (&draft.weights, prompt.kv_cache_dtype) {
+                (Weights::F32(draft), CacheDtype::F32) => speculative::generate::<T, f32, f32, f32>(&llama, draft, input_ids, max_tokens, k, sampler, stop),
+                (Weights::F32(draft), CacheDtype::BF16) => speculative::generate::<T, f32, bf16, bf16>(&llama, draft, input_ids, max_tokens, k, sampler, stop),
+                (Weights::F32(draft), CacheDtype::Int8) => speculative::generate::<T, f32, i8, i8>(&llama, draft, input_ids, max_tokens, k, sampler, stop),
+                (Weights::BF16(draft), CacheDtype::F32) => speculative::generate::<T, bf16, f32, f32>(&llama, draft, input_ids, max_tokens, k, sampler, stop),
+                (Weights::BF16(draft), CacheDtype::BF16) => speculative::generate::<T, bf16, bf16, bf16>(&llama, draft, input_ids, max_tokens, k, sampler, stop),
+                (Weights::BF16(draft), CacheDtype::Int8) => speculative::generate::<T, bf16, i8, i8>(&llama, draft, input_ids, max_tokens, k, sampler, stop),
+            }?;
+            speculation = Some(output.stats());
+            (Generation { tokens: output.tokens, finish_reason: output.finish_reason, logprobs: Vec::new() }, None)
+        }
+        (None, None) => (match cache {
+            ChatCache::F32(cached) => llama.generate_cached(input_ids, max_tokens, sampler, prompt.overflow, stop, prompt.logprobs, cached),
+            ChatCache::BF16(cached) => llama.generate_cached(input_ids, max_tokens, sampler, prompt.overflow, stop, prompt.logprobs, cached),
+            ChatCache::Int8(cached) => llama.generate_cached(input_ids, max_tokens, sampler, prompt.overflow, stop, prompt.logprobs, cached),
+        }?, None),
+    };
+    let mut text = tokenizer.decode(&output.tokens, true).unwrap();
+    stop.trim(&mut text, output.finish_reason);
+    if let Some(text_stream) = &text_stream {
+        text_stream.finish(&text);
+    }
+    let token_info = |id: u32, logprob: f32| TokenInfo { token: tokenizer.decode(&[id], false).unwrap(), id, logprob };
+    let logprobs = prompt.logprobs.map(|_| {
+        output.logprobs.iter().map(|lp| LogprobEntry {
+            token: token_info(lp.token_id, lp.logprob),
+            top_logprobs: lp.top.iter().map(|&(id, logprob)| token_info(id, logprob)).collect(),
+        }).collect()
+    });
+    let user_token_ids = tokenizer.encode(prompt.user_message.as_str(), false).unwrap().get_ids().to_vec();
+    Ok(Reply { text, seed: sampler.seed(), finish_reason: output.finish_reason, logprobs, beams, draft: speculation, user_token_ids, token_ids: output.tokens })
+}
+
+// Run a chat request against the chat model, with the session's history.
+fn run_chat(models: &ChatModels, prompt: &Request, settings: &Settings, cancel: CancelToken, on_text: Option<&dyn Fn(&str)>, cache: Option<&mut ChatCache>) -> Result<Reply, Box<dyn Error>> {
+    match &models.chat.weights {
+        Weights::F32(llama) => chat_func(llama, models, prompt, settings, cancel, on_text, cache),
+        Weights::BF16(llama) => chat_func(llama, models, prompt, settings, cancel, on_text, cache),
+    }
+}
+
+// A token that times out after the shorter of the server's and the request's limits.
+fn cancel_token(settings: &Settings, requested_secs: Option<u64>) -> CancelToken {
+    let limit = [settings.generation.timeout_secs, requested_secs].into_iter().flatten().min();
+    match limit {
+        Some(secs) => CancelToken::new().with_timeout(Duration::from_secs(secs)),
+        None => CancelToken::new(),
+    }
+}
+
+// Run a chat request on the compute pool and `store` its reply. Generation
+// stops once the client goes away: a plain reply is cancelled when this future
+// is dropped, a streamed one when sending the next piece of text fails.
+async fn respond<F>(request: &HttpRequest, models: ChatModels, prompt: Request, settings: Arc<Settings>, pool: &ComputePool, store: F) -> HttpResponse
+where F: FnOnce(&Request, &Reply) -> std::io::Result<()> + Send + 'static
+{
+    let cancel = cancel_token(&settings, prompt.timeout_secs);
+    let key = api_key(request);
+    if prompt.stream {
+        let (events, body) = mpsc::unbounded_channel();
+        let spawned = pool.spawn(move |start| {
+            if let Err(e) = start {
+                let _ = events.send(error_event(&e.to_string()));
+                return;
+            }
+            let send = |text: &str| {
+                if events.send(text_event(text)).is_err() {
+                    cancel.cancel();
+                }
+            };
+            let result = run_chat(&models, &prompt, &settings, cancel.clone(), Some(&send), None);
+            if let (Some(key), Ok(reply)) = (&key, &result) {
+                key.record_tokens(reply.token_ids.len());
+            }
+            let event = match result {
+                Ok(reply) if reply.finish_reason == FinishReason::Cancelled => return,
+                Ok(reply) => match store(&prompt, &reply) {
+                    Ok(()) => done_event(reply.finish_reason, reply.seed, reply.draft, reply.logprobs),
+                    Err(e) => error_event(&store_error(e).1),
+                },
+                Err(e) => error_event(&e.to_string()),
+            };
+            let _ = events.send(event);
+        });
+        if let Err(e) = spawned {
+            return pool_error_response(e);
+        }
+        return HttpResponse::Ok()
+            .content_type("text/event-stream")
+            .insert_header(("Cache-Control", "no-cache"))
+            .streaming(UnboundedReceiverStream::new(body).map(Ok::<_, Infallible>));
+    }
+    let _cancel_on_drop = CancelOnDrop(cancel.clone());
+    if let Some(peer) = request.conn_data::<PeerSocket>() {
+        peer.clone().watch(cancel.clone());
+    }
+    let result = pool.submit(move || {
+        let reply = run_chat(&models, &prompt, &settings, cancel, None, None)
+            .map_err(|e| (StatusCode::BAD_REQUEST, e.to_string()))?;
+        if let Some(key) = &key {
+            key.record_tokens(reply.token_ids.len());
+        }
+        // Nobody is left to see a cancelled reply, so the session stays as it was
+        if reply.finish_reason != FinishReason::Cancelled {
+            store(&prompt, &reply)
+                .map_err(store_error)?;
+        }
+        Ok(reply)
+    });
+    let result = match result {
+        Ok(result) => result.await,
+        Err(e) => return pool_error_response(e),
+    };
+    match result {
+        Ok(Ok(reply)) => reply_response(reply),
+        Ok(Err((status, message))) => HttpResponse::build(status).body(message),
+        Err(e) => pool_error_response(e),
+    }
+}
+
+// The key the request was authorized with, when authentication is on.
+fn api_key(request: &HttpRequest) -> Option<Arc<KeyState>> {
+    request.extensions().get::<Arc<KeyState>>().cloned()
+}
+
+// Check the bearer token of every request but the probes, which load balancers
+// make without one, and `/metrics` if it is configured public for scrapers.
+fn authorize(auth: &Auth, public_metrics: bool, request: &ServiceRequest) -> Result<(), AuthError> {
+    let public = match request.path() {
+        "/healthz" | "/readyz" => true,
+        "/metrics" => public_metrics,
+        _ => false,
+    };
+    if !auth.enabled() || public {
+        return Ok(());
+    }
+    let header = request.headers().get(header::AUTHORIZATION).and_then(|value| value.to_str().ok());
+    let key = auth.authorize(header)?;
+    request.extensions_mut().insert(key);
+    Ok(())
+}
+
+fn auth_error_response(e: AuthError) -> HttpResponse {
+    match e {
+        AuthError::RateLimited { retry_after } => HttpResponse::TooManyRequests()
+            .insert_header(("Retry-After", retry_after.as_secs_f64().ceil().to_string()))
+            .body(e.to_string()),
+        AuthError::Missing | AuthError::Invalid => HttpResponse::Unauthorized()
+            .insert_header((header::WWW_AUTHENTICATE, "Bearer"))
+            .body(e.to_string()),
+    }
+}
+
+// A full queue asks the client to back off; a stuck one means the server is overloaded.
+fn pool_error_response(e: PoolError) -> HttpResponse {
+    let mut response = match e {
+        PoolError::QueueFull => HttpResponse::TooManyRequests(),
+        PoolError::Expired | PoolError::Stopped => HttpResponse::ServiceUnavailable(),
+    };
+    response.insert_header(("Retry-After", "1")).body(e.to_string())
+}
+
+// Unknown models are not found; those still loading or broken make the server unavailable.
+fn model_error_response(e: ModelError) -> HttpResponse {
+    match e {
+        ModelError::NotFound(_) => HttpResponse::NotFound().body(e.to_string()),
+        _ => HttpResponse::ServiceUnavailable().body(e.to_string()),
+    }
+}
+
+fn reply_response(reply: Reply) -> HttpResponse {
+    let mut response = HttpResponse::Ok();
+    response
+        .insert_header(("X-Seed", reply.seed))
+        .insert_header(("X-Finish-Reason", reply.finish_reason.to_string()));
+    if let Some(draft) = reply.draft {
+        response
+            .insert_header(("X-Drafted-Tokens", draft.drafted))
+            .insert_header(("X-Accepted-Tokens", draft.accepted))
+            .insert_header(("X-Acceptance-Rate", draft.acceptance_rate.to_string()));
+    }
+    if reply.logprobs.is_none() && reply.beams.is_none() {
+        return response.body(reply.text);
+    }
+    response.json(JsonReply { text: &reply.text, logprobs: reply.logprobs.as_deref(), beams: reply.beams.as_deref() })
 }
 
 #[post("/chat")]
-async fn chat(mut prompt_json: web::Json<Request>, app_data: web::Data<Arc<DashMap<String, String>>>) -> impl Responder {
-    println!("\n{}\nreceive request from session_id = {{{}}}\n{:?}",(|| "-".repeat(50))(),&prompt_json.session_id,&prompt_json);
-    let map = app_data.as_ref();
-    let history = map.get(&prompt_json.session_id).map(|v| v.to_string()).unwrap_or_default();
-    prompt_json.history = history;
-    let project_dir = env!("CARGO_MANIFEST_DIR"); 
-    let model_dir = PathBuf::from(project_dir).join("models").join("chat");
-    let config = File::open(model_dir.join("config.json")).unwrap();
-    let config: LlamaConfigJson = serde_json::from_reader(config).unwrap();
-    let ans = match config.torch_dtype.as_ref() {
-        "bfloat16" => chat_func::<bf16>(model_dir, &prompt_json),
-        "float32" => chat_func::<f32>(model_dir, &prompt_json),
-        _ => todo!()
-    };
-    map.insert(prompt_json.session_id.clone(),format!("{0}<|im_start|>system\n{1}<|im_end|>\n<|im_start|>user\n{2}<|im_end|>\n<|im_start|>assistant{3}\n",prompt_json.history,prompt_json.system_message,prompt_json.user_message,ans.clone()));
-    HttpResponse::Ok().body(ans)
+async fn chat(request: HttpRequest, mut prompt_json: web::Json<Request>, sessions: web::Data<dyn SessionStore>, models: web::Data<ModelRegistry>, pool: web::Data<ComputePool>, settings: web::Data<Settings>) -> impl Responder {
+    tracing::info!(session_id = %prompt_json.session_id, "chat request {:?}", &prompt_json);
+    let models = match ChatModels::get(&models, &prompt_json) {
+        Ok(models) => models,
+        Err(e) => return model_error_response(e),
+    };
+    let key = api_key(&request);
+    if let Err(e) = check_owner(&**sessions, &prompt_json.session_id, key.as_deref()) {
+        return HttpResponse::Forbidden().body(e);
+    }
+    prompt_json.history = sessions.history(&prompt_json.session_id);
+    respond(&request, models, prompt_json.into_inner(), settings.into_inner(), &pool, move |prompt, reply| {
+        store_turn(&**sessions, prompt, reply, key.as_deref())
+    }).await
+}
+
+// Whether a request made with `key` may use a session owned by `owner`:
+// every request when authentication is off, admin keys always, and other
+// keys only their own sessions.
+fn may_access(key: Option<&KeyState>, owner: Option<&str>) -> bool {
+    key.is_none_or(|key| key.owner().may_access(owner))
+}
+
+// Refuse to continue a session started with another key.
+fn check_owner(sessions: &dyn SessionStore, id: &str, key: Option<&KeyState>) -> Result<(), String> {
+    match sessions.get(id) {
+        Some(session) if !may_access(key, session.owner.as_deref()) => Err(format!("session {id} belongs to another key")),
+        _ => Ok(()),
+    }
+}
+
+// Another key may have started the session while the reply was generated,
+// which `push_turn` refuses like `check_owner` does.
+fn store_error(e: std::io::Error) -> (StatusCode, String) {
+    match e.kind() {
+        std::io::ErrorKind::PermissionDenied => (StatusCode::FORBIDDEN, e.to_string()),
+        _ => (StatusCode::INTERNAL_SERVER_ERROR, format!("failed to store the session: {e}")),
+    }
+}
+
+// The session `id`, if it exists and `key` may see it.
+fn owned_session(sessions: &dyn SessionStore, id: &str, key: Option<&KeyState>) -> Option<Session> {
+    sessions.get(id).filter(|session| may_access(key, session.owner.as_deref()))
+}
+
+// Append the user message and its reply to the request's session.
+fn store_turn(sessions: &dyn SessionStore, prompt: &Request, reply: &Reply, key: Option<&KeyState>) -> std::io::Result<()> {
+    sessions.push_turn(
+        &prompt.session_id,
+        prompt.user_id.as_deref(),
+        key.map(KeyState::owner),
+        SessionMessage::new("user", prompt.user_message.as_str(), reply.user_token_ids.clone()),
+        SessionMessage::new("assistant", reply.text.as_str(), reply.token_ids.clone()),
+    )
+}
+
+// Sessions of other keys are left out, and look to them as if they did not exist.
+#[get("/sessions")]
+async fn list_sessions(request: HttpRequest, sessions: web::Data<dyn SessionStore>) -> impl Responder {
+    let key = api_key(&request);
+    let mut summaries = sessions.list();
+    summaries.retain(|summary| may_access(key.as_deref(), summary.owner.as_deref()));
+    HttpResponse::Ok().json(summaries)
+}
+
+#[get("/sessions/{id}")]
+async fn get_session(request: HttpRequest, id: web::Path<String>, sessions: web::Data<dyn SessionStore>) -> impl Responder {
+    match owned_session(&**sessions, &id, api_key(&request).as_deref()) {
+        Some(session) => HttpResponse::Ok().json(session),
+        None => HttpResponse::NotFound().body(format!("no session {id}")),
+    }
+}
+
+#[delete("/sessions/{id}")]
+async fn delete_session(request: HttpRequest, id: web::Path<String>, sessions: web::Data<dyn SessionStore>) -> impl Responder {
+    if owned_session(&**sessions, &id, api_key(&request).as_deref()).is_none() {
+        return HttpResponse::NotFound().body(format!("no session {id}"));
+    }
+    match sessions.delete(&id) {
+        Ok(true) => HttpResponse::NoContent().finish(),
+        Ok(false) => HttpResponse::NotFound().body(format!("no session {id}")),
+        Err(e) => HttpResponse::InternalServerError().body(format!("failed to store the session: {e}")),
+    }
+}
+
+// Drop the last user message and its reply.
+#[delete("/sessions/{id}/last")]
+async fn delete_last_turn(request: HttpRequest, id: web::Path<String>, sessions: web::Data<dyn SessionStore>) -> impl Responder {
+    if owned_session(&**sessions, &id, api_key(&request).as_deref()).is_none() {
+        return HttpResponse::NotFound().body(format!("no session {id}"));
+    }
+    match sessions.pop_turn(&id) {
+        Ok(Some(_)) => HttpResponse::Ok().json(sessions.get(&id)),
+        Ok(None) => HttpResponse::NotFound().body(format!("no turn to drop in session {id}")),
+        Err(e) => HttpResponse::InternalServerError().body(format!("failed to store the session: {e}")),
+    }
+}
+
+// Answer the last user message again. The body takes the same settings as
+// `/chat`; its session id and user message are ignored.
+#[post("/sessions/{id}/regenerate")]
+async fn regenerate(request: HttpRequest, id: web::Path<String>, mut prompt_json: web::Json<Request>, sessions: web::Data<dyn SessionStore>, models: web::Data<ModelRegistry>, pool: web::Data<ComputePool>, settings: web::Data<Settings>) -> impl Responder {
+    let models = match ChatModels::get(&models, &prompt_json) {
+        Ok(models) => models,
+        Err(e) => return model_error_response(e),
+    };
+    let Some(session) = owned_session(&**sessions, &id, api_key(&request).as_deref()) else {
+        return HttpResponse::NotFound().body(format!("no session {id}"));
+    };
+    let Some((user, _)) = session.last_turn() else {
+        return HttpResponse::NotFound().body(format!("no turn to regenerate in session {id}"));
+    };
+    prompt_json.session_id = id.to_string();
+    prompt_json.user_message = user.content.clone();
+    prompt_json.history = session.messages[..session.messages.len() - 2].iter().map(SessionMessage::message).collect();
+    respond(&request, models, prompt_json.into_inner(), settings.into_inner(), &pool, move |prompt, reply| {
+        sessions.replace_reply(&prompt.session_id, SessionMessage::new("assistant", reply.text.as_str(), reply.token_ids.clone())).map(|_| ())
+    }).await
+}
+
+#[derive(Deserialize)]
+struct TokenizeRequest {
+    model: String,
+    // Either plain text, or messages to render with the model's chat template
+    #[serde(default)]
+    text: Option<String>,
+    #[serde(default)]
+    messages: Option<Vec<Message>>,
+    // End the rendered messages with the opening of the assistant's turn, as
+    // `/chat` does
+    #[serde(default = "default_add_generation_prompt")]
+    add_generation_prompt: bool,
+    // Add the tokenizer's special tokens, such as BOS, to plain text
+    #[serde(default)]
+    add_special_tokens: bool,
+}
+
+const fn default_add_generation_prompt() -> bool {
+    true
+}
+
+#[derive(Serialize)]
+struct TokenizeReply {
+    ids: Vec<u32>,
+    count: usize,
+    context_length: usize,
+}
+
+#[derive(Deserialize)]
+struct DetokenizeRequest {
+    model: String,
+    ids: Vec<u32>,
+    #[serde(default = "default_skip_special_tokens")]
+    skip_special_tokens: bool,
+}
+
+const fn default_skip_special_tokens() -> bool {
+    true
+}
+
+// Token ids of a text or a conversation, to count them against the context
+// length before sending a request.
+#[post("/tokenize")]
+async fn tokenize(body: web::Json<TokenizeRequest>, models: web::Data<ModelRegistry>) -> impl Responder {
+    let model = match models.get(&body.model) {
+        Ok(model) => model,
+        Err(e) => return model_error_response(e),
+    };
+    let ids = match (&body.text, &body.messages) {
+        (Some(text), None) => model.encode(text, body.add_special_tokens),
+        (None, Some(messages)) => model.template.render(messages, body.add_generation_prompt)
+            .map_err(|e| e.into())
+            .and_then(|prompt| model.encode(&prompt, false)),
+        _ => return HttpResponse::BadRequest().body("expected one of text and messages"),
+    };
+    match ids {
+        Ok(ids) => HttpResponse::Ok().json(TokenizeReply { count: ids.len(), ids, context_length: model.info().context_length }),
+        Err(e) => HttpResponse::BadRequest().body(e.to_string()),
+    }
+}
+
+#[post("/detokenize")]
+async fn detokenize(body: web::Json<DetokenizeRequest>, models: web::Data<ModelRegistry>) -> impl Responder {
+    let model = match models.get(&body.model) {
+        Ok(model) => model,
+        Err(e) => return model_error_response(e),
+    };
+    match model.decode(&body.ids, body.skip_special_tokens) {
+        Ok(text) => HttpResponse::Ok().json(serde_json::json!({ "text": text })),
+        Err(e) => HttpResponse::BadRequest().body(e.to_string()),
+    }
+}
+
+// What a client sends over /ws/chat: a message to answer, with the fields of a
+// `/chat` body, or a cancel of the reply being generated.
+#[derive(Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum SocketRequest {
+    Message(Box<Request>),
+    Cancel,
+}
+
+#[derive(Deserialize)]
+struct SocketParams {
+    session_id: String,
+    #[serde(default)]
+    user_id: Option<String>,
+}
+
+// One /ws/chat connection. All its messages go to the same session, and each
+// reply starts from the KV cache the previous one left instead of running the
+// whole history again.
+struct ChatSocket {
+    session_id: String,
+    user_id: Option<String>,
+    key: Option<Arc<KeyState>>,
+    sessions: web::Data<dyn SessionStore>,
+    models: web::Data<ModelRegistry>,
+    pool: web::Data<ComputePool>,
+    settings: web::Data<Settings>,
+    cache: Arc<Mutex<ChatCache>>,
+    // The reply being generated, until its last event is sent
+    running: Arc<Mutex<Option<CancelToken>>>,
+    events: mpsc::UnboundedSender<String>,
+}
+
+impl ChatSocket {
+    fn handle(&self, text: &str) {
+        match serde_json::from_str(text) {
+            Ok(SocketRequest::Message(prompt)) => {
+                if let Err(e) = self.answer(*prompt) {
+                    let _ = self.events.send(ws_error(&e));
+                }
+            }
+            Ok(SocketRequest::Cancel) => self.cancel(),
+            Err(e) => {
+                let _ = self.events.send(ws_error(&format!("invalid message: {e}")));
+            }
+        }
+    }
+
+    fn cancel(&self) {
+        if let Some(cancel) = &*self.running.lock().unwrap() {
+            cancel.cancel();
+        }
+    }
+
+    // Generate a reply to `prompt` on the compute pool, streaming it as events.
+    fn answer(&self, mut prompt: Request) -> Result<(), String> {
+        let mut running = self.running.lock().unwrap();
+        if running.is_some() {
+            return Err("a reply is still being generated".into());
+        }
+        if let Some(key) = &self.key {
+            key.admit(Instant::now()).map_err(|e| e.to_string())?;
+        }
+        let models = ChatModels::get(&self.models, &prompt).map_err(|e| e.to_string())?;
+        check_owner(&**self.sessions, &self.session_id, self.key.as_deref())?;
+        prompt.session_id = self.session_id.clone();
+        prompt.user_id = self.user_id.clone();
+        prompt.history = self.sessions.history(&self.session_id);
+        let cancel = cancel_token(&self.settings, prompt.timeout_secs);
+        *running = Some(cancel.clone());
+        let (sessions, settings, key) = (self.sessions.clone(), self.settings.clone(), self.key.clone());
+        let (cache, job_running, events) = (self.cache.clone(), self.running.clone(), self.events.clone());
+        let spawned = self.pool.spawn(move |start| {
+            let result = start.map_err(|e| e.to_string()).and_then(|()| {
+                let send = |text: &str| {
+                    if events.send(ws_text(text)).is_err() {
+                        cancel.cancel();
+                    }
+                };
+                let mut cache = cache.lock().unwrap();
+                run_chat(&models, &prompt, &settings, cancel.clone(), Some(&send), Some(&mut cache)).map_err(|e| e.to_string())
+            });
+            let event = match result {
+                Ok(reply) => {
+                    if let Some(key) = &key {
+                        key.record_tokens(reply.token_ids.len());
+                    }
+                    // A cancelled reply leaves the session as it was
+                    let stored = match reply.finish_reason {
+                        FinishReason::Cancelled => Ok(()),
+                        _ => store_turn(&**sessions, &prompt, &reply, key.as_deref()),
+                    };
+                    match stored {
+                        Ok(()) => ws_done(reply.finish_reason, reply.seed, reply.draft, reply.logprobs),
+                        Err(e) => ws_error(&store_error(e).1),
+                    }
+                }
+                Err(e) => ws_error(&e),
+            };
+            // Free before the client hears of it, so that it may follow up right away
+            job_running.lock().unwrap().take();
+            let _ = events.send(event);
+        });
+        if let Err(e) = spawned {
+            running.take();
+            return Err(e.to_string());
+        }
+        Ok(())
+    }
+}
+
+// Chat over a WebSocket bound to `?session_id=`. The client sends JSON
+// messages of type "message" or "cancel" and receives events of type "text",
+// "done" and "error"; a new message may follow once a reply is done.
+#[get("/ws/chat")]
+async fn ws_chat(request: HttpRequest, body: web::Payload, params: web::Query<SocketParams>, sessions: web::Data<dyn SessionStore>, models: web::Data<ModelRegistry>, pool: web::Data<ComputePool>, settings: web::Data<Settings>) -> impl Responder {
+    if let Err(e) = check_owner(&**sessions, &params.session_id, api_key(&request).as_deref()) {
+        return HttpResponse::Forbidden().body(e);
+    }
+    let (response, mut socket, messages) = match actix_ws::handle(&request, body) {
+        Ok(handshake) => handshake,
+        Err(e) => return HttpResponse::from_error(e),
+    };
+    let (events, mut outgoing) = mpsc::unbounded_channel::<String>();
+    let params = params.into_inner();
+    let chat_socket = ChatSocket {
+        session_id: params.session_id,
+        user_id: params.user_id,
+        key: api_key(&request),
+        sessions,
+        models,
+        pool,
+        settings,
+        cache: Arc::new(Mutex::new(ChatCache::new(CacheDtype::default()))),
+        running: Arc::new(Mutex::new(None)),
+        events,
+    };
+    // Events go out from a task of their own, so that a slow client does not
+    // hold up reading its cancel
+    let mut sender = socket.clone();
+    rt::spawn(async move {
+        while let Some(event) = outgoing.recv().await {
+            if sender.text(event).await.is_err() {
+                break;
+            }
+        }
+    });
+    rt::spawn(async move {
+        let mut messages = messages.aggregate_continuations();
+        while let Some(Ok(message)) = messages.recv().await {
+            match message {
+                AggregatedMessage::Text(text) => chat_socket.handle(&text),
+                AggregatedMessage::Binary(_) => {
+                    let _ = chat_socket.events.send(ws_error("expected a JSON text message"));
+                }
+                AggregatedMessage::Ping(bytes) => {
+                    if socket.pong(&bytes).await.is_err() {
+                        break;
+                    }
+                }
+                AggregatedMessage::Pong(_) => {}
+                AggregatedMessage::Close(_) => break,
+            }
+        }
+        // Nobody is left to read the reply in progress
+        chat_socket.cancel();
+        let _ = socket.close(None).await;
+    });
+    response
+}
+
+// Requests and generated tokens of every key, for keys marked admin.
+#[get("/admin/usage")]
+async fn usage(request: HttpRequest, auth: web::Data<Auth>) -> impl Responder {
+    if !auth.enabled() {
+        return HttpResponse::NotFound().body("authentication is off");
+    }
+    match api_key(&request) {
+        Some(key) if key.key.admin => HttpResponse::Ok().json(auth.usage()),
+        _ => HttpResponse::Forbidden().body("needs an admin key"),
+    }
+}
+
+// The process is up; says nothing about the models.
+#[get("/healthz")]
+async fn healthz() -> impl Responder {
+    HttpResponse::Ok().body("ok")
+}
+
+// Ready to serve once every model has loaded; the body gives each model's state.
+#[get("/readyz")]
+async fn readyz(models: web::Data<ModelRegistry>) -> impl Responder {
+    let status = models.status();
+    match models.is_ready() {
+        true => HttpResponse::Ok().json(status),
+        false => HttpResponse::ServiceUnavailable().json(status),
+    }
+}
+
+#[get("/v1/models/{name}")]
+async fn model_info(name: web::Path<String>, models: web::Data<ModelRegistry>) -> impl Responder {
+    match models.get(&name) {
+        Ok(model) => HttpResponse::Ok().json(model.info()),
+        Err(e) => model_error_response(e),
+    }
+}
+
+#[get("/metrics")]
+async fn export_metrics(sessions: web::Data<dyn SessionStore>) -> impl Responder {
+    METRICS.active_sessions.set(sessions.list().len() as i64);
+    HttpResponse::Ok()
+        .content_type("text/plain; version=0.0.4")
+        .body(METRICS.render())
 }
 
 #[actix_web::main]
-async fn main() -> std::io::Result<()> {
-    let app_data = web::Data::new(Arc::new(DashMap::<String,String>::new())); 
-    println!("Server running on http://127.0.0.1:8080");
-    HttpServer::new(move || {
+async fn main() -> Result<(), Box<dyn Error>> {
+    let cli = Cli::parse();
+    let settings = Settings::load(&cli)?;
+    let _trace = settings.init()?;
+    if let Some(Command::Eval(args)) = &cli.command {
+        return eval::run(args, &settings);
+    }
+    let sessions = web::Data::from(open_store(&settings.sessions)?);
+    let models = Arc::new(ModelRegistry::default());
+    models.load("story", settings.models.story.clone(), settings.compute.dtype);
+    models.load("chat", settings.models.chat.clone(), settings.compute.dtype);
+    if let Some(draft) = &settings.models.draft {
+        models.load("draft", draft.clone(), settings.compute.dtype);
+    }
+    let models = web::Data::from(models);
+    let auth = match &settings.auth.key_file {
+        Some(path) => Arc::new(Auth::from_file(path)?),
+        None => {
+            tracing::warn!("no API key file given, authentication is off");
+            Arc::new(Auth::default())
+        }
+    };
+    let auth = web::Data::from(auth);
+    let compute = &settings.compute;
+    let pool = web::Data::new(ComputePool::new(compute.max_concurrent, compute.max_queue, Duration::from_secs(compute.queue_timeout_secs)));
+    let bind = settings.server.bind.clone();
+    let workers = settings.server.workers;
+    let settings = web::Data::new(settings);
+    let mut server = HttpServer::new(move || {
         App::new()
-            .app_data(app_data.clone())
+            .app_data(settings.clone())
+            .app_data(sessions.clone())
+            .app_data(models.clone())
+            .app_data(pool.clone())
+            .app_data(auth.clone())
+            .wrap_fn({
+                let auth = auth.clone();
+                let public_metrics = settings.auth.public_metrics;
+                move |req, srv| {
+                    let response = match authorize(&auth, public_metrics, &req) {
+                        Ok(()) => Ok(srv.call(req)),
+                        Err(e) => Err(req.into_response(auth_error_response(e))),
+                    };
+                    async move {
+                        match response {
+                            Ok(response) => response.await,
+                            Err(rejected) => Ok(rejected),
+                        }
+                    }
+                }
+            })
+            // Count every response by the route it matched
+            .wrap_fn(|req, srv| {
+                let endpoint = req.match_pattern().unwrap_or_else(|| "unmatched".into());
+                let response = srv.call(req);
+                async move {
+                    let response = response.await?;
+                    METRICS.requests.with_label_values(&[endpoint.as_str(), response.status().as_str()]).inc();
+                    Ok(response)
+                }
+            })
             .service(story)
             .service(chat)
+            .service(list_sessions)
+            .service(get_session)
+            .service(delete_session)
+            .service(delete_last_turn)
+            .service(regenerate)
+            .service(ws_chat)
+            .service(tokenize)
+            .service(detokenize)
+            .service(usage)
+            .service(healthz)
+            .service(readyz)
+            .service(model_info)
+            .service(export_metrics)
     })
-    .bind(("127.0.0.1", 8080))?
-    .run()
-    .await
+    .on_connect(|connection, data| {
+        if let Some(peer) = PeerSocket::from_connection(connection) {
+            data.insert(peer);
+        }
+    });
+    if let Some(workers) = workers {
+        server = server.workers(workers);
+    }
+    let server = server.bind(bind.as_str())?;
+    tracing::info!("server running on http://{bind}");
+    server.run().await?;
+    Ok(())
 }
 
 #[test]
 fn infer_test(){
     let dir = "chat";
     let project_dir = env!("CARGO_MANIFEST_DIR");
-    let model_dir = PathBuf::from(project_dir).join("models").join(dir);
-    let config = File::open(model_dir.join("config.json")).unwrap();
-    let config: LlamaConfigJson = serde_json::from_reader(config).unwrap();
-    let prompt_json = Request {session_id:"".to_string(),history:"".to_string(),system_message:"you are a helpful assistant".to_string(),user_message:"who are you?".to_string()};
-    let ans = match config.torch_dtype.as_ref() {
-        "bfloat16" => chat_func::<bf16>(model_dir, &prompt_json),
-        "float32" => chat_func::<f32>(model_dir, &prompt_json),
-        _ => todo!()
-    };
-    println!("{}",ans);
+    let model_dir = std::path::PathBuf::from(project_dir).join("models").join(dir);
+    let model = Arc::new(LoadedModel::load(dir, &model_dir, None).unwrap());
+    let prompt_json = Request {session_id:"".to_string(),user_id:None,history:Vec::new(),system_message:"you are a helpful assistant".to_string(),user_message:"who are you?".to_string(),overflow:ContextOverflow::default(),kv_cache_dtype:CacheDtype::default(),sampling:SamplingParams::default(),stop:Vec::new(),stop_token_ids:Vec::new(),logprobs:None,grammar:None,json_schema:None,stream:false,timeout_secs:None,beam_width:None,beam:BeamParams::default(),draft_tokens:None};
+    let ans = run_chat(&ChatModels { chat: model, draft: None }, &prompt_json, &Settings::default(), CancelToken::new(), None, None);
+    println!("{}",ans.unwrap().text);
+}
+
+#[test]
+fn test_session_access() {
+    use crate::auth::ApiKey;
+    let key = |name: &str, admin| ApiKey { name: name.into(), key: format!("sk-{name}"), requests_per_minute: None, tokens_per_minute: None, admin };
+    let auth = Auth::new(vec![key("alice", false), key("bob", false), key("ops", true)]).unwrap();
+    let [alice, bob, ops] = ["alice", "bob", "ops"].map(|name| auth.authorize(Some(&format!("Bearer sk-{name}"))).unwrap());
+    let sessions = crate::session::MemoryStore::new(None, None);
+    let (user, assistant) = (SessionMessage::new("user", "hi", vec![1]), SessionMessage::new("assistant", "hello", vec![2]));
+    sessions.push_turn("a", None, Some(alice.owner()), user, assistant).unwrap();
+
+    assert!(owned_session(&sessions, "a", Some(&alice)).is_some());
+    assert!(owned_session(&sessions, "a", Some(&bob)).is_none());
+    assert!(owned_session(&sessions, "a", Some(&ops)).is_some());
+    // Without authentication every session is open to everyone
+    assert!(owned_session(&sessions, "a", None).is_some());
+    assert!(check_owner(&sessions, "a", Some(&bob)).is_err());
+    assert!(check_owner(&sessions, "new", Some(&bob)).is_ok());
+    // A turn for a session another key started meanwhile is refused
+    let (user, assistant) = (SessionMessage::new("user", "hi", vec![1]), SessionMessage::new("assistant", "hello", vec![2]));
+    let e = sessions.push_turn("a", None, Some(bob.owner()), user.clone(), assistant.clone()).unwrap_err();
+    assert_eq!(store_error(e).0, StatusCode::FORBIDDEN);
+    sessions.push_turn("a", None, Some(ops.owner()), user, assistant).unwrap();
+    assert_eq!(sessions.get("a").unwrap().owner.as_deref(), Some("alice"));
 }
@@ -0,0 +1,181 @@
+use prometheus::{
+    register_histogram_with_registry, register_int_counter_vec_with_registry,
+    register_int_counter_with_registry, register_int_gauge_with_registry, Encoder, Histogram,
+    IntCounter, IntCounterVec, IntGauge, Registry, TextEncoder,
+};
+use std::sync::LazyLock;
+use std::time::Instant;
+
+// Process-wide metrics, exported in the Prometheus text format by /metrics.
+pub static METRICS: LazyLock<Metrics> = LazyLock::new(Metrics::new);
+
+pub struct Metrics {
+    registry: Registry,
+    pub requests: IntCounterVec,           // by endpoint and status
+    pub time_to_first_token: Histogram,    // seconds
+    pub inter_token_latency: Histogram,    // seconds
+    pub tokens_per_second: Histogram,      // one sample per generation
+    pub generated_tokens: IntCounter,
+    pub queue_depth: IntGauge,             // generations waiting for a compute slot
+    pub active_generations: IntGauge,
+    pub active_sessions: IntGauge,
+    pub kv_cache_bytes: IntGauge,          // allocated by generations in flight
+}
+
+impl Metrics {
+    fn new() -> Self {
+        let registry = Registry::new_custom(Some("llm".into()), None).unwrap();
+        let latency_buckets = vec![0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 30.0];
+        Metrics {
+            requests: register_int_counter_vec_with_registry!(
+                "http_requests_total",
+                "HTTP requests by endpoint and status",
+                &["endpoint", "status"],
+                registry
+            )
+            .unwrap(),
+            time_to_first_token: register_histogram_with_registry!(
+                "time_to_first_token_seconds",
+                "Time from the start of a generation to its first token",
+                latency_buckets.clone(),
+                registry
+            )
+            .unwrap(),
+            inter_token_latency: register_histogram_with_registry!(
+                "inter_token_latency_seconds",
+                "Time between consecutive generated tokens",
+                latency_buckets,
+                registry
+            )
+            .unwrap(),
+            tokens_per_second: register_histogram_with_registry!(
+                "tokens_per_second",
+                "Decoding speed of each generation",
+                vec![1.0, 2.0, 5.0, 10.0, 20.0, 50.0, 100.0, 200.0, 500.0],
+                registry
+            )
+            .unwrap(),
+            generated_tokens: register_int_counter_with_registry!(
+                "generated_tokens_total",
+                "Tokens generated over all requests",
+                registry
+            )
+            .unwrap(),
+            queue_depth: register_int_gauge_with_registry!(
+                "queue_depth",
+                "Generation requests waiting for a compute slot",
+                registry
+            )
+            .unwrap(),
+            active_generations: register_int_gauge_with_registry!(
+                "active_generations",
+                "Generation requests running on the compute pool",
+                registry
+            )
+            .unwrap(),
+            active_sessions: register_int_gauge_with_registry!(
+                "active_sessions",
+                "Chat sessions held by the session store",
+                registry
+            )
+            .unwrap(),
+            kv_cache_bytes: register_int_gauge_with_registry!(
+                "kv_cache_bytes",
+                "Memory held by the KV caches of running generations",
+                registry
+            )
+            .unwrap(),
+            registry,
+        }
+    }
+
+    // Everything registered, in the Prometheus text exposition format.
+    pub fn render(&self) -> String {
+        let mut buf = Vec::new();
+        TextEncoder::new().encode(&self.registry.gather(), &mut buf).unwrap();
+        String::from_utf8(buf).unwrap()
+    }
+}
+
+// Adds to a gauge until dropped, so early returns are accounted for too.
+pub struct GaugeGuard {
+    gauge: &'static IntGauge,
+    amount: i64,
+}
+
+impl GaugeGuard {
+    pub fn add(gauge: &'static IntGauge, amount: i64) -> Self {
+        gauge.add(amount);
+        GaugeGuard { gauge, amount }
+    }
+}
+
+impl Drop for GaugeGuard {
+    fn drop(&mut self) {
+        self.gauge.sub(self.amount);
+    }
+}
+
+// Times the tokens of one generation into the latency and throughput
+// histograms, whichever way they are decoded.
+pub struct TokenTimer {
+    start: Instant,
+    last: Option<Instant>,
+}
+
+impl TokenTimer {
+    pub fn start() -> Self {
+        TokenTimer { start: Instant::now(), last: None }
+    }
+
+    // `n` tokens came out of one step, as with beam search or speculative
+    // decoding; the step's latency is shared among them. The first token of
+    // the generation counts towards the time to first token instead.
+    pub fn tokens(&mut self, n: usize) {
+        if n == 0 {
+            return;
+        }
+        let now = Instant::now();
+        match self.last {
+            None => METRICS.time_to_first_token.observe((now - self.start).as_secs_f64()),
+            Some(last) => {
+                let latency = (now - last).as_secs_f64() / n as f64;
+                (0..n).for_each(|_| METRICS.inter_token_latency.observe(latency));
+            }
+        }
+        self.last = Some(now);
+    }
+
+    // The generation is over after `n` tokens.
+    pub fn finish(&self, n: usize) {
+        let elapsed = self.start.elapsed().as_secs_f64();
+        if n > 0 && elapsed > 0. {
+            METRICS.tokens_per_second.observe(n as f64 / elapsed);
+        }
+    }
+}
+
+#[test]
+fn test_metrics_render() {
+    let metrics = Metrics::new();
+    metrics.requests.with_label_values(&["/chat", "200"]).inc();
+    metrics.time_to_first_token.observe(0.2);
+    metrics.kv_cache_bytes.add(1024);
+    let text = metrics.render();
+    assert!(text.contains(r#"llm_http_requests_total{endpoint="/chat",status="200"} 1"#));
+    assert!(text.contains("llm_time_to_first_token_seconds_count 1"));
+    assert!(text.contains("llm_kv_cache_bytes 1024"));
+}
+
+#[test]
+fn test_token_timer() {
+    // Other tests generate at the same time, so the counts only have lower bounds
+    let (first, later) = (METRICS.time_to_first_token.get_sample_count(), METRICS.inter_token_latency.get_sample_count());
+    let mut timer = TokenTimer::start();
+    timer.tokens(0);
+    timer.tokens(1);
+    timer.tokens(3);
+    timer.finish(4);
+    assert!(METRICS.time_to_first_token.get_sample_count() > first);
+    assert!(METRICS.inter_token_latency.get_sample_count() >= later + 3);
+}
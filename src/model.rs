@@ -1,26 +1,74 @@
-use std::fs::File;
 use std::{f32, vec};
 use crate::operators::ToF32;
-use crate::config::LlamaConfigJson;
-use crate::kvcache::KVCache;
+use crate::config::{GenerationConfigJson, LlamaConfigJson};
+use crate::grammar::GrammarError;
+use crate::kvcache::{KVCache, KVElem, PromptCache};
+use crate::logprobs::TokenLogprob;
+use crate::metrics::{GaugeGuard, TokenTimer, METRICS};
 use crate::operators as OP;
 use crate::params::{LLamaParams,Load};
+use crate::sampler::Sampler;
+use crate::stop::{FinishReason, StopCriteria};
 use crate::tensor::Tensor;
 use safetensors::SafeTensors;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::fs::File;
+use std::io;
 use std::path::Path;
 use std::time::Instant;
+use tracing::{debug, debug_span, info_span, trace_span};
 
 
-// 定义宏 `measure_time`
-macro_rules! measure_time {
-    ($label:expr, $code:block) => {{
-        let start = Instant::now();
-        let result = { $code };
-        let elapsed = start.elapsed();
-        println!("Execute {{{}}} for {:.2?}", $label, elapsed);
-        result
-    }};
+// What to do when the prompt plus the generated tokens would exceed `max_seq_len`.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq)]
+#[serde(tag = "policy", rename_all = "snake_case")]
+pub enum ContextOverflow {
+    // Reject the request up front.
+    #[default]
+    Error,
+    // Drop the oldest prompt tokens so that `max_len` new tokens still fit,
+    // and stop early if the context fills up anyway.
+    TruncateLeft,
+    // Keep the first `keep` tokens (e.g. the system prompt) and, whenever the
+    // context is full, drop half of the tokens after them.
+    ContextShift { keep: usize },
+}
+
+#[derive(Debug, PartialEq)]
+pub enum GenerateError {
+    ContextOverflow { len: usize, max_seq_len: usize },
+    InvalidGrammar(GrammarError),
+}
+
+impl fmt::Display for GenerateError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            GenerateError::ContextOverflow { len, max_seq_len } => write!(
+                f,
+                "sequence of {len} tokens exceeds the maximum context length of {max_seq_len}"
+            ),
+            GenerateError::InvalidGrammar(e) => e.fmt(f),
+        }
+    }
 }
+
+impl std::error::Error for GenerateError {}
+
+impl From<GrammarError> for GenerateError {
+    fn from(e: GrammarError) -> Self {
+        GenerateError::InvalidGrammar(e)
+    }
+}
+
+pub struct Generation {
+    pub tokens: Vec<u32>,
+    pub finish_reason: FinishReason,
+    // One entry per token in `tokens`, only when `generate` was asked for them
+    pub logprobs: Vec<TokenLogprob>,
+}
+
+#[derive(Clone)]
 pub struct Llama<T> {
     vocab: usize,           // vocab size
     n_layers: usize,        // number of layers
@@ -33,17 +81,30 @@ pub struct Llama<T> {
     rope_theta: f32,        // rope theta for rope initialization
     max_seq_len: usize,     // maximum sequence length
     params: LLamaParams<T>, // trained weights of this model
+    #[allow(unused)]
     bos_token_id: u32,      // start token id
-    eos_token_id: u32,      // end token id
+    eos_token_ids: Vec<u32>, // end token ids, from config.json and generation_config.json
+    fingerprint: u64,       // hash of config and weights, identifies cache snapshots
 }
 
 impl<T> Llama<T> 
 where T: Default + Copy + ToF32 + Load
 {
     pub fn from_safetensors(model_dir: impl AsRef<Path>) -> Self {
-        let config = File::open(model_dir.as_ref().join("config.json")).unwrap();
-        let config: LlamaConfigJson = serde_json::from_reader(config).unwrap();
+        let _span = info_span!("load", model_dir = %model_dir.as_ref().display()).entered();
+        let config_file = std::fs::read(model_dir.as_ref().join("config.json")).unwrap();
+        let config: LlamaConfigJson = serde_json::from_slice(&config_file).unwrap();
         let model_file = std::fs::read(model_dir.as_ref().join("model.safetensors")).unwrap();
+        let fingerprint = fnv1a(&model_file, fnv1a(&config_file, FNV_OFFSET));
+        let mut eos_token_ids = config.eos_token_id.to_vec();
+        if let Ok(generation_config) = File::open(model_dir.as_ref().join("generation_config.json")) {
+            let generation_config: GenerationConfigJson = serde_json::from_reader(generation_config).unwrap();
+            for id in generation_config.eos_token_id.map(|ids| ids.to_vec()).unwrap_or_default() {
+                if !eos_token_ids.contains(&id) {
+                    eos_token_ids.push(id);
+                }
+            }
+        }
         let safetensor = SafeTensors::deserialize(&model_file).unwrap();
         let params = LLamaParams::<T>::from_safetensors(&safetensor, &config);
 
@@ -58,77 +119,157 @@ where T: Default + Copy + ToF32 + Load
             eps: config.rms_norm_eps,
             rope_theta: config.rope_theta,
             max_seq_len: config.max_position_embeddings,
-            params: params,
+            params,
             bos_token_id: config.bos_token_id,
-            eos_token_id: config.eos_token_id,
+            eos_token_ids,
+            fingerprint,
         }
     }
 
-    pub fn new_cache(&self) -> KVCache<f32> {
-        KVCache::new(self.n_layers, self.max_seq_len, self.n_kv_h * self.dqkv, 0)
+    pub fn new_cache<C: KVElem>(&self) -> KVCache<C> {
+        KVCache::new(self.n_layers, self.max_seq_len, self.n_kv_h, self.dqkv, 0)
+    }
+
+    // Snapshot `cache` to disk, tagged with this model's fingerprint.
+    #[allow(unused)]
+    pub fn save_cache<C: KVElem>(&self, cache: &KVCache<C>, path: impl AsRef<Path>) -> io::Result<()> {
+        cache.save(path, self.fingerprint)
+    }
+
+    // Restore a snapshot taken by `save_cache` of this very model.
+    #[allow(unused)]
+    pub fn load_cache<C: KVElem>(&self, path: impl AsRef<Path>) -> io::Result<KVCache<C>> {
+        KVCache::load(path, self.fingerprint)
+    }
+
+    // Logits of the next token after the last input position, (1, vocab).
+    pub fn forward<C: KVElem>(&self, input: &Tensor<u32>, cache: &mut KVCache<C>) -> Tensor<f32> {
+        self.run(input, cache, 1)
+    }
+
+    // Logits after every input position, (seq, vocab). Row i scores the token
+    // following input i, which is what evaluating a text needs.
+    pub fn forward_all<C: KVElem>(&self, input: &Tensor<u32>, cache: &mut KVCache<C>) -> Tensor<f32> {
+        self.run(input, cache, input.size())
+    }
+
+    // Shorten the context so that a cache of element type `C` fits in `bytes`.
+    pub fn limit_cache<C: KVElem>(&mut self, bytes: usize) {
+        let per_token = KVCache::<C>::bytes_per_token(self.n_layers, self.n_kv_h, self.dqkv);
+        self.max_seq_len = self.max_seq_len.min(bytes / per_token).max(1);
+    }
+
+    // Weights of the model; tied embeddings count once even though they are loaded twice.
+    pub fn parameter_count(&self, tie_word_embeddings: bool) -> usize {
+        let total: usize = self.params.tensors().map(|t| t.size()).sum();
+        match tie_word_embeddings {
+            true => total - self.params.lm_head.size(),
+            false => total,
+        }
+    }
+
+    // Memory the weights take up.
+    pub fn weight_bytes(&self) -> usize {
+        self.params.tensors().map(|t| t.size() * std::mem::size_of::<T>()).sum()
+    }
+
+    pub fn max_seq_len(&self) -> usize {
+        self.max_seq_len
     }
 
-    pub fn forward(&self, input: &Tensor<u32>, cache: &mut KVCache<f32>) -> Tensor<f32> {
+    pub fn vocab(&self) -> usize {
+        self.vocab
+    }
+
+    pub fn eos_token_ids(&self) -> &[u32] {
+        &self.eos_token_ids
+    }
+
+    pub fn is_eos(&self, token_id: u32) -> bool {
+        self.eos_token_ids.contains(&token_id)
+    }
+
+    // Run the decoder over `input` and project the last `n_logits` positions.
+    fn run<C: KVElem>(&self, input: &Tensor<u32>, cache: &mut KVCache<C>, n_logits: usize) -> Tensor<f32> {
         let seq_len = input.size();
         let past_seq_len = cache.len();
+        assert!(
+            past_seq_len + seq_len <= cache.max_seq_len(),
+            "sequence of {} tokens does not fit in the cache of {}",
+            past_seq_len + seq_len,
+            cache.max_seq_len()
+        );
+        let _span = debug_span!("forward", seq_len, past_seq_len).entered();
         cache.increment(seq_len);
         let total_seq_len = past_seq_len + seq_len;
         let n_groups = self.n_q_h / self.n_kv_h;
 
         // Some pre-allocated buffers that will be reused
-        let mut residual = Tensor::<f32>::default(&vec![seq_len, self.d]);
-        let mut hidden_states = Tensor::<f32>::default(&vec![seq_len, self.d]);
-        let mut q_buf = Tensor::<f32>::default(&vec![seq_len, self.n_q_h * self.dqkv]);
+        let mut residual = Tensor::<f32>::default(&[seq_len, self.d]);
+        let mut hidden_states = Tensor::<f32>::default(&[seq_len, self.d]);
+        let mut q_buf = Tensor::<f32>::default(&[seq_len, self.n_q_h * self.dqkv]);
+        let mut k_buf = Tensor::<f32>::default(&[seq_len, self.n_kv_h * self.dqkv]);
+        let mut v_buf = Tensor::<f32>::default(&[seq_len, self.n_kv_h * self.dqkv]);
         let mut att_scores =
-            Tensor::<f32>::default(&vec![self.n_kv_h, n_groups, seq_len, total_seq_len]);
-        let mut gate_buf = Tensor::<f32>::default(&vec![seq_len, self.di]);
-        let mut up_buf = Tensor::<f32>::default(&vec![seq_len, self.di]);
+            Tensor::<f32>::default(&[self.n_kv_h, n_groups, seq_len, total_seq_len]);
+        let mut gate_buf = Tensor::<f32>::default(&[seq_len, self.di]);
+        let mut up_buf = Tensor::<f32>::default(&[seq_len, self.di]);
 
         // Computation Starts Here
         // Embedding lookup
         OP::gather(&mut residual, input, &self.params.embedding_table); // (seq, dim)
 
         for layer in 0..self.n_layers {
+            let _layer = trace_span!("layer", layer).entered();
+            let attention = trace_span!("attention").entered();
             OP::rms_norm(
                 &mut hidden_states,
                 &residual,
                 &self.params.rms_att_w[layer],
                 self.eps,
             );// (seq, dim)
-            let q = (&mut q_buf).reshape(&vec![seq_len, self.n_q_h * self.dqkv]); // (seq, n_h * dqkv)
-            let k = &mut cache.k_cache(layer, past_seq_len); // (seq, n_kv_h * dqkv)
-            let v = &mut cache.v_cache(layer, past_seq_len); // (seq, n_kv_h * dqkv)
+            let q = q_buf.reshape(&[seq_len, self.n_q_h * self.dqkv]); // (seq, n_h * dqkv)
+            let k = k_buf.reshape(&[seq_len, self.n_kv_h * self.dqkv]); // (seq, n_kv_h * dqkv)
+            let v = &mut v_buf; // (seq, n_kv_h * dqkv)
             OP::matmul_transb(q, 0., &hidden_states, &self.params.wq[layer], 1.0);
             OP::matmul_transb(k, 0., &hidden_states, &self.params.wk[layer], 1.0);
             OP::matmul_transb(v, 0., &hidden_states, &self.params.wv[layer], 1.0);
             OP::rope(
-                q.reshape(&vec![seq_len, self.n_q_h, self.dqkv]),
+                q.reshape(&[seq_len, self.n_q_h, self.dqkv]),
                 past_seq_len,
                 self.rope_theta,
             );
             OP::rope(
-                k.reshape(&vec![seq_len, self.n_kv_h, self.dqkv]),
+                k.reshape(&[seq_len, self.n_kv_h, self.dqkv]),
                 past_seq_len,
                 self.rope_theta,
             );
+            cache.store_k(layer, past_seq_len, k);
+            cache.store_v(layer, past_seq_len, v);
 
-            let full_k = &mut cache.k_cache(layer, 0); // (total_seq, n_kv_h * dqkv)
-            let full_v = &mut cache.v_cache(layer, 0); // (total_seq, n_kv_h * dqkv)
+            let full_k = &cache.k_cache(layer, 0); // (total_seq, n_kv_h * dqkv)
+            let full_v = &cache.v_cache(layer, 0); // (total_seq, n_kv_h * dqkv)
+            let k_scale = &cache.k_scale(layer, 0); // (total_seq, n_kv_h)
+            let v_scale = &cache.v_scale(layer, 0); // (total_seq, n_kv_h)
             
             //let full_v = full_v.transpose(); 
-            self_attention(&mut hidden_states, &mut att_scores, q, &full_k, &full_v, self.n_kv_h, n_groups, seq_len, total_seq_len, self.dqkv);
+            self_attention(&mut hidden_states, &mut att_scores, q, full_k, k_scale, full_v, v_scale, self.n_kv_h, n_groups, seq_len, total_seq_len, self.dqkv);
             OP::matmul_transb(&mut residual, 1.0, &hidden_states, &self.params.wo[layer], 1.0);
+            drop(attention);
+            let _mlp = trace_span!("mlp").entered();
             mlp(&mut residual, &mut hidden_states, &mut gate_buf, &mut up_buf, 
                 &self.params.w_up[layer], &self.params.w_down[layer], &self.params.w_gate[layer],
                 &self.params.rms_ffn_w[layer], self.eps
             );
         }
 
-        // No matter what seq_len, the output is always a 1D vector of length vocab,
-        // which contains the probabilities for the next token.
-        let mut logits = Tensor::<f32>::default(&vec![1, self.vocab]);
-        let mut hidden_states = hidden_states.slice((seq_len - 1) * self.d, &vec![1, self.d]);
-        let residual = residual.slice((seq_len - 1) * self.d, &vec![self.d]);
+        // Only the requested trailing positions are normalized and projected,
+        // each row is a vector of length vocab scoring the token after it.
+        let _span = trace_span!("lm_head", n_logits).entered();
+        let start = seq_len - n_logits;
+        let mut logits = Tensor::<f32>::default(&[n_logits, self.vocab]);
+        let mut hidden_states = hidden_states.slice(start * self.d, &[n_logits, self.d]);
+        let residual = residual.slice(start * self.d, &[n_logits, self.d]);
         
         OP::rms_norm(
             &mut hidden_states,
@@ -142,36 +283,157 @@ where T: Default + Copy + ToF32 + Load
         logits
     }
 
-    pub fn generate(
+    pub fn generate<C: KVElem>(
+        &self,
+        token_ids: &[u32],
+        max_len: usize,
+        sampler: &mut Sampler,
+        overflow: ContextOverflow,
+        stop: &StopCriteria,
+        top_logprobs: Option<usize>,
+    ) -> Result<Generation, GenerateError>{
+        self.generate_cached::<C>(token_ids, max_len, sampler, overflow, stop, top_logprobs, &mut PromptCache::default())
+    }
+
+    // Like `generate`, but starting from whatever prefix of the prompt `cached`
+    // holds from an earlier generation, and leaving this one's tokens in it.
+    #[allow(clippy::too_many_arguments)]
+    pub fn generate_cached<C: KVElem>(
         &self,
         token_ids: &[u32],
         max_len: usize,
-        top_p: f32,
-        top_k: u32,
-        temperature: f32,
-    ) -> Vec<u32>{
+        sampler: &mut Sampler,
+        overflow: ContextOverflow,
+        stop: &StopCriteria,
+        top_logprobs: Option<usize>,
+        cached: &mut PromptCache<C>,
+    ) -> Result<Generation, GenerateError>{
+        let _span = info_span!("generate", prompt_tokens = token_ids.len(), max_len).entered();
+        let token_ids = self.fit_prompt(token_ids, max_len, overflow)?;
+        let reused = cached.reuse(&token_ids, self.max_seq_len, || self.new_cache());
+        let (cache, cached_tokens) = (cached.cache.as_mut().unwrap(), &mut cached.tokens);
+        let mut history = token_ids.clone(); // what the sampler penalizes repetitions of
         let mut result = Vec::<u32>::new();
-        let mut cache = self.new_cache();
-        let mut prompt = Tensor::new(token_ids.to_vec(),&vec![token_ids.len()]);
+        let mut logprobs = Vec::<TokenLogprob>::new();
+        let mut finish_reason = FinishReason::Length;
+        let mut shifted = false;
+        let _cache_bytes = GaugeGuard::add(&METRICS.kv_cache_bytes, cache.bytes() as i64);
+        let mut prompt = Tensor::new(token_ids[reused..].to_vec(),&[token_ids.len() - reused]);
+        let start = Instant::now();
+        let mut timer = TokenTimer::start();
         while result.len() < max_len {
-            let logits = measure_time!("forward",{self.forward(&prompt, &mut cache)});
-            let token_id = OP::random_sample(&logits, top_p, top_k, temperature);
-            if token_id==self.eos_token_id{
+            if let Some(reason) = stop.interrupted() {
+                finish_reason = reason;
                 break;
             }
+            if cache.len() + prompt.size() > self.max_seq_len {
+                match overflow {
+                    ContextOverflow::ContextShift { keep } if keep < cache.len() => {
+                        let n_discard = ((cache.len() - keep) / 2).max(1);
+                        self.shift_context(cache, keep, n_discard);
+                        // The shifted keys no longer sit where a fresh run of
+                        // the same tokens would put them, so none may be reused
+                        cached_tokens.clear();
+                        shifted = true;
+                    }
+                    ContextOverflow::TruncateLeft => {
+                        finish_reason = FinishReason::ContextFull;
+                        break;
+                    }
+                    _ => return Err(GenerateError::ContextOverflow {
+                        len: cache.len() + prompt.size(),
+                        max_seq_len: self.max_seq_len,
+                    }),
+                }
+            }
+            let logits = match result.len() {
+                0 => debug_span!("prefill", tokens = prompt.size(), reused).in_scope(|| self.forward(&prompt, cache)),
+                _ => self.forward(&prompt, cache),
+            };
+            if !shifted {
+                cached_tokens.extend_from_slice(prompt.data());
+            }
+            let token_id = trace_span!("sample").in_scope(|| sampler.sample(&logits, &history));
+            timer.tokens(1);
+            if self.is_eos(token_id) || stop.is_stop_token(token_id) {
+                finish_reason = FinishReason::StopToken(token_id);
+                break;
+            }
+            if let Some(top_n) = top_logprobs {
+                logprobs.push(TokenLogprob::new(logits.data(), token_id, top_n));
+            }
+            history.push(token_id);
             result.push(token_id);
-            prompt = Tensor::new(vec![token_id],&vec![1]);
+            stop.emit(token_id);
+            if let Some(i) = stop.find_string(&result) {
+                finish_reason = FinishReason::StopString(i);
+                break;
+            }
+            prompt = Tensor::new(vec![token_id],&[1]);
+        }
+        METRICS.generated_tokens.inc_by(result.len() as u64);
+        timer.finish(result.len());
+        debug!(tokens = result.len(), reused, %finish_reason, elapsed = ?start.elapsed(), "generation finished");
+        Ok(Generation { tokens: result, finish_reason, logprobs })
+    }
+
+    // Make the prompt fit into the context window according to `overflow`.
+    fn fit_prompt(&self, token_ids: &[u32], max_len: usize, overflow: ContextOverflow) -> Result<Vec<u32>, GenerateError> {
+        let budget = match overflow {
+            ContextOverflow::ContextShift { .. } => self.max_seq_len,
+            _ => self.max_seq_len.saturating_sub(max_len).max(1),
+        };
+        if token_ids.len() <= budget {
+            return Ok(token_ids.to_vec());
+        }
+        match overflow {
+            ContextOverflow::Error => Err(GenerateError::ContextOverflow {
+                len: token_ids.len() + max_len,
+                max_seq_len: self.max_seq_len,
+            }),
+            ContextOverflow::TruncateLeft => Ok(token_ids[token_ids.len() - budget..].to_vec()),
+            ContextOverflow::ContextShift { keep } => {
+                let keep = keep.min(budget);
+                let mut ids = token_ids[..keep].to_vec();
+                ids.extend_from_slice(&token_ids[token_ids.len() - (budget - keep)..]);
+                Ok(ids)
+            }
+        }
+    }
+
+    // Drop `n_discard` cached positions after the first `keep` ones and rotate
+    // the remaining keys back so that positions stay contiguous.
+    fn shift_context<C: KVElem>(&self, cache: &mut KVCache<C>, keep: usize, n_discard: usize) {
+        cache.discard(keep, n_discard);
+        for layer in 0..self.n_layers {
+            let mut k = cache.load_k(layer, keep); // (seq - keep, n_kv_h * dqkv)
+            let seq_len = k.size() / (self.n_kv_h * self.dqkv);
+            OP::rope_shift(
+                k.reshape(&[seq_len, self.n_kv_h, self.dqkv]),
+                -(n_discard as isize),
+                self.rope_theta,
+            );
+            cache.store_k(layer, keep, k.reshape(&[seq_len, self.n_kv_h * self.dqkv]));
         }
-        result
     }
 }
 
-fn self_attention(
+const FNV_OFFSET: u64 = 0xcbf29ce484222325;
+
+// 64-bit FNV-1a, stable across builds unlike `DefaultHasher`
+fn fnv1a(bytes: &[u8], hash: u64) -> u64 {
+    bytes.iter().fold(hash, |h, b| (h ^ *b as u64).wrapping_mul(0x100000001b3))
+}
+
+#[allow(clippy::too_many_arguments)]
+fn self_attention<C: KVElem>(
     hidden_states: &mut Tensor<f32>, // (seq, n_kv_h * n_groups * dqkv)
     att_scores: &mut Tensor<f32>,    // (n_kv_h, n_groups, seq, total_seq)
     q: &Tensor<f32>,                 // (seq, n_kv_h * n_groups * dqkv)
-    k: &Tensor<f32>,                 // (total_seq, n_kv_h * dqkv)
-    v: &Tensor<f32>,                 // (total_seq, n_kv_h * dqkv) or (n_kv_h * dqkv, total_seq)
+    k: &Tensor<C>,                   // (total_seq, n_kv_h * dqkv)
+    k_scale: &Tensor<f32>,           // (total_seq, n_kv_h)
+    v: &Tensor<C>,                   // (total_seq, n_kv_h * dqkv) or (n_kv_h * dqkv, total_seq)
+    v_scale: &Tensor<f32>,           // (total_seq, n_kv_h)
     n_kv_h: usize,
     n_groups: usize,
     seq_len: usize,
@@ -183,9 +445,10 @@ fn self_attention(
     let n_q_h = n_kv_h * n_groups;
     let q_dim = n_q_h * dqkv;
     let kv_dim = n_kv_h * dqkv;
-    let att_dim = n_q_h * total_seq_len;
     let q_data = q.data();
     let k_data = k.data();
+    let k_scale = k_scale.data();
+    let v_scale = v_scale.data();
     //转置v矩阵，shape为(n_kv_h * dqkv, total_seq_len)
     let v = v.transpose();
     let v_data = v.data();
@@ -193,7 +456,7 @@ fn self_attention(
     let hidden_data = unsafe { hidden_states.data_mut() };
     
     // 计算attention矩阵
-    // attention的shape为(seq_len, n_q_h * total_seq_len)
+    // attention的shape为(n_q_h, seq_len, total_seq_len)，与masked_softmax的布局一致
     // query矩阵的每个head的shape为(seq_len, dqkv)
     // key矩阵的每个head的shape为(total_seq_len, dqkv)
     for h in 0..n_q_h {
@@ -201,29 +464,32 @@ fn self_attention(
             for col in 0..total_seq_len {
                 let q_base = row * q_dim + h * dqkv;
                 let k_base = col * kv_dim + h/n_groups * dqkv;
-                att_data[row * att_dim + h * total_seq_len + col] = (0..dqkv).map(|d| q_data[q_base + d] * k_data[k_base + d]).sum::<f32>() / (dqkv as f32).sqrt();
+                // cached keys are dequantized on read
+                let scale = k_scale[col * n_kv_h + h/n_groups];
+                att_data[(h * seq_len + row) * total_seq_len + col] = (0..dqkv).map(|d| q_data[q_base + d] * k_data[k_base + d].to_f32()).sum::<f32>() * scale / (dqkv as f32).sqrt();
             }
         }
     }
     OP::masked_softmax(att_scores);
      
     let att = att_scores.data(); 
-    // attention的shape为(seq_len, n_q_h * total_seq_len)
+    // attention的shape为(n_q_h, seq_len, total_seq_len)
     // v的shape为(n_kv_h * dqkv, total_seq_len)
     // attention的每个head的shape为(seq_len, total_seq_len)
     //
     for h in 0..n_q_h {
         for row in 0..seq_len {
             for col in 0..dqkv {
-                let a_base = row * att_dim + h * total_seq_len;
+                let a_base = (h * seq_len + row) * total_seq_len;
                 //let v_base = col + h/n_groups * dqkv;
                 let v_base = col * total_seq_len + h/n_groups * dqkv * total_seq_len;
-                hidden_data[row * q_dim + h * dqkv + col] = (0..total_seq_len).map(|d| att[a_base + d] * v_data[v_base + d]).sum::<f32>();
+                hidden_data[row * q_dim + h * dqkv + col] = (0..total_seq_len).map(|d| att[a_base + d] * v_data[v_base + d].to_f32() * v_scale[d * n_kv_h + h/n_groups]).sum::<f32>();
             }
         }
     } 
 }
 
+#[allow(clippy::too_many_arguments)]
 fn mlp<T>(
     residual: &mut Tensor<f32>,
     hidden_states: &mut Tensor<f32>,
@@ -245,6 +511,7 @@ where T:Copy + Clone + Default + ToF32,
 }
 
 #[test]
+#[allow(clippy::useless_vec)]
 pub fn test_mlp() {
     let seq_len = 4;
     let d = 2;
@@ -311,3 +578,208 @@ pub fn test_load_safetensors() {
     assert!(float_eq(&model.params.wo[0].data()[100], &0.01965332, 1e-6));
 
 }
+
+#[test]
+pub fn test_context_overflow() {
+    use std::path::PathBuf;
+    let project_dir = env!("CARGO_MANIFEST_DIR");
+    let model_dir = PathBuf::from(project_dir).join("models").join("story");
+    let model = Llama::<f32>::from_safetensors(model_dir);
+    let prompt = vec![1u32; model.max_seq_len + 10];
+
+    assert_eq!(
+        model.generate::<f32>(&prompt, 8, &mut Sampler::new(0.8, 30, 1.), ContextOverflow::Error, &StopCriteria::none(), None).err(),
+        Some(GenerateError::ContextOverflow { len: model.max_seq_len + 18, max_seq_len: model.max_seq_len })
+    );
+    assert_eq!(model.fit_prompt(&prompt, 8, ContextOverflow::TruncateLeft).unwrap().len(), model.max_seq_len - 8);
+    let shifted = model.fit_prompt(&prompt, 8, ContextOverflow::ContextShift { keep: 4 }).unwrap();
+    assert_eq!(shifted.len(), model.max_seq_len);
+
+    // Shifting the context of a filled cache leaves room to keep decoding
+    let mut cache = model.new_cache::<f32>();
+    model.forward(&Tensor::new(shifted, &[model.max_seq_len]), &mut cache);
+    model.shift_context(&mut cache, 4, 100);
+    assert_eq!(cache.len(), model.max_seq_len - 100);
+    model.forward(&Tensor::new(vec![1], &[1]), &mut cache);
+}
+
+#[test]
+pub fn test_context_shift_rerotates_keys() {
+    use std::path::PathBuf;
+    let project_dir = env!("CARGO_MANIFEST_DIR");
+    let model_dir = PathBuf::from(project_dir).join("models").join("story");
+    let model = Llama::<f32>::from_safetensors(model_dir);
+    let ids = [1u32, 400, 35, 600, 72, 900, 13];
+
+    // Drop tokens 1..3 out of a cache holding all seven tokens...
+    let mut shifted = model.new_cache::<f32>();
+    model.forward(&Tensor::new(ids.to_vec(), &[ids.len()]), &mut shifted);
+    model.shift_context(&mut shifted, 1, 2);
+
+    // ...first-layer keys only depend on token and position, so they must
+    // match a cache that never saw the dropped tokens
+    let kept = [1u32, 600, 72, 900, 13];
+    let mut fresh = model.new_cache::<f32>();
+    model.forward(&Tensor::new(kept.to_vec(), &[kept.len()]), &mut fresh);
+    assert_eq!(shifted.len(), fresh.len());
+    let (k, expected) = (shifted.k_cache(0, 0), fresh.k_cache(0, 0));
+    assert!(k.data().iter().zip(expected.data()).all(|(a, b)| (a - b).abs() < 1e-4));
+}
+
+#[test]
+pub fn test_quantized_cache_drift() {
+    use std::path::PathBuf;
+    use half::bf16;
+    let project_dir = env!("CARGO_MANIFEST_DIR");
+    let model_dir = PathBuf::from(project_dir).join("models").join("story");
+    let model = Llama::<f32>::from_safetensors(model_dir);
+    let ids: Vec<u32> = (0..64).map(|i| (i * 37 + 11) % model.vocab as u32).collect();
+
+    // Feed the tokens one by one so that every step reads back the cache
+    fn last_logits<C: KVElem>(model: &Llama<f32>, ids: &[u32]) -> Vec<f32> {
+        let mut cache = model.new_cache::<C>();
+        let mut logits = Tensor::<f32>::default(&[1, model.vocab]);
+        for &id in ids {
+            logits = model.forward(&Tensor::new(vec![id], &[1]), &mut cache);
+        }
+        logits.data().to_vec()
+    }
+    let max_drift = |a: &[f32], b: &[f32]| a.iter().zip(b).fold(0f32, |m, (x, y)| m.max((x - y).abs()));
+    let argmax = |a: &[f32]| a.iter().enumerate().max_by(|x, y| x.1.total_cmp(y.1)).unwrap().0;
+
+    let reference = last_logits::<f32>(&model, &ids);
+    let logits_bf16 = last_logits::<bf16>(&model, &ids);
+    let logits_int8 = last_logits::<i8>(&model, &ids);
+    let (drift_bf16, drift_int8) = (max_drift(&reference, &logits_bf16), max_drift(&reference, &logits_int8));
+    assert!(drift_bf16 < 0.1);
+    assert!(drift_int8 < 0.25);
+    assert_eq!(argmax(&reference), argmax(&logits_bf16));
+    assert_eq!(argmax(&reference), argmax(&logits_int8));
+}
+
+#[test]
+pub fn test_cache_snapshot() {
+    use std::path::PathBuf;
+    use half::bf16;
+    let project_dir = env!("CARGO_MANIFEST_DIR");
+    let model_dir = PathBuf::from(project_dir).join("models").join("story");
+    let model = Llama::<f32>::from_safetensors(model_dir);
+    let path = std::env::temp_dir().join(format!("story-cache-{}.bin", std::process::id()));
+
+    let mut cache = model.new_cache::<bf16>();
+    model.forward(&Tensor::new(vec![1, 400, 35, 600], &[4]), &mut cache);
+    model.save_cache(&cache, &path).unwrap();
+    let mut restored = model.load_cache::<bf16>(&path).unwrap();
+    std::fs::remove_file(&path).unwrap();
+
+    assert_eq!(restored.len(), 4);
+    let expected = model.forward(&Tensor::new(vec![72], &[1]), &mut cache);
+    let logits = model.forward(&Tensor::new(vec![72], &[1]), &mut restored);
+    assert_eq!(logits.data(), expected.data());
+}
+
+#[test]
+pub fn test_forward_all() {
+    use std::path::PathBuf;
+    let project_dir = env!("CARGO_MANIFEST_DIR");
+    let model_dir = PathBuf::from(project_dir).join("models").join("story");
+    let model = Llama::<f32>::from_safetensors(model_dir);
+    let ids = vec![1u32, 400, 35, 600];
+
+    let all = model.forward_all(&Tensor::new(ids.clone(), &[4]), &mut model.new_cache::<f32>());
+    assert_eq!(all.shape(), &vec![4, model.vocab]);
+    // Every row matches the logits of feeding the prefix on its own
+    let mut cache = model.new_cache::<f32>();
+    for (i, id) in ids.into_iter().enumerate() {
+        let logits = model.forward(&Tensor::new(vec![id], &[1]), &mut cache);
+        assert!(logits.close_to(&all.slice(i * model.vocab, &[1, model.vocab]), 1e-3));
+    }
+}
+
+#[test]
+pub fn test_seeded_generate() {
+    use std::path::PathBuf;
+    let project_dir = env!("CARGO_MANIFEST_DIR");
+    let model_dir = PathBuf::from(project_dir).join("models").join("story");
+    let model = Llama::<f32>::from_safetensors(model_dir);
+    let generate = |seed: u64| {
+        let sampler = &mut Sampler::new(0.9, 50, 1.).with_seed(seed);
+        model.generate::<f32>(&[1, 400], 24, sampler, ContextOverflow::Error, &StopCriteria::none(), None).unwrap().tokens
+    };
+    assert_eq!(generate(1234), generate(1234));
+    assert_ne!(generate(1234), generate(4321));
+}
+
+#[test]
+pub fn test_generate_logprobs() {
+    use std::path::PathBuf;
+    let project_dir = env!("CARGO_MANIFEST_DIR");
+    let model_dir = PathBuf::from(project_dir).join("models").join("story");
+    let model = Llama::<f32>::from_safetensors(model_dir);
+    let sampler = &mut Sampler::new(0.8, 1, 1.);
+    let output = model.generate::<f32>(&[1, 400], 10, sampler, ContextOverflow::Error, &StopCriteria::none(), Some(3)).unwrap();
+    assert_eq!(output.logprobs.len(), output.tokens.len());
+    for (lp, &token_id) in output.logprobs.iter().zip(&output.tokens) {
+        // Greedy decoding always emits the most likely token
+        assert_eq!(lp.token_id, token_id);
+        assert_eq!(lp.top.len(), 3);
+        assert_eq!(lp.top[0], (token_id, lp.logprob));
+        assert!(lp.top.windows(2).all(|w| w[0].1 >= w[1].1));
+    }
+}
+
+#[test]
+pub fn test_generate_cancel() {
+    use crate::stop::CancelToken;
+    use std::cell::RefCell;
+    use std::path::PathBuf;
+    let project_dir = env!("CARGO_MANIFEST_DIR");
+    let model_dir = PathBuf::from(project_dir).join("models").join("story");
+    let model = Llama::<f32>::from_safetensors(model_dir);
+    let sampler = &mut Sampler::new(0.8, 1, 1.);
+    let token = CancelToken::new();
+    let streamed = RefCell::new(Vec::new());
+    let stop = StopCriteria::none().with_cancel(token.clone()).with_on_token(|id| {
+        streamed.borrow_mut().push(id);
+        if streamed.borrow().len() == 3 {
+            token.cancel();
+        }
+    });
+    let output = model.generate::<f32>(&[1, 400], 50, sampler, ContextOverflow::Error, &stop, None).unwrap();
+    assert_eq!(output.finish_reason, FinishReason::Cancelled);
+    assert_eq!(output.tokens, *streamed.borrow());
+    assert_eq!(output.tokens.len(), 3);
+}
+
+#[test]
+pub fn test_generate_cached() {
+    use std::path::PathBuf;
+    let project_dir = env!("CARGO_MANIFEST_DIR");
+    let model_dir = PathBuf::from(project_dir).join("models").join("story");
+    let model = Llama::<f32>::from_safetensors(model_dir);
+    let generate = |prompt: &[u32], cached: &mut PromptCache<f32>| {
+        let sampler = &mut Sampler::new(0.8, 1, 1.);
+        model.generate_cached(prompt, 8, sampler, ContextOverflow::Error, &StopCriteria::none(), None, cached).unwrap().tokens
+    };
+    let mut cached = PromptCache::default();
+    let first = generate(&[1, 400], &mut cached);
+    // Everything but the last sampled token went through the cache
+    assert_eq!(cached.tokens[..2], [1, 400]);
+    assert_eq!(cached.tokens[2..], first[..first.len() - 1]);
+
+    // A follow-up extending the first exchange continues from the cache
+    let mut follow_up = vec![1, 400];
+    follow_up.extend(&first);
+    follow_up.push(13);
+    let continued = generate(&follow_up, &mut cached);
+    assert_eq!(cached.tokens[..follow_up.len()], follow_up);
+    assert_eq!(continued, generate(&follow_up, &mut PromptCache::default()));
+
+    // Nothing is kept for reuse once the context has shifted
+    let long: Vec<u32> = (0..model.max_seq_len as u32 - 2).map(|i| 100 + i % 50).collect();
+    let sampler = &mut Sampler::new(0.8, 1, 1.);
+    let overflow = ContextOverflow::ContextShift { keep: 4 };
+    model.generate_cached(&long, 8, sampler, overflow, &StopCriteria::none(), None, &mut cached).unwrap();
+    assert!(cached.tokens.is_empty());
+    assert_eq!(cached.reuse(&long, model.max_seq_len, || model.new_cache()), 0);
+}
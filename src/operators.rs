@@ -1,8 +1,10 @@
 use std::f32;
 use half::bf16;
+use rand::Rng;
+use rayon::prelude::*;
 use crate::tensor::Tensor;
 
-pub trait ToF32 {
+pub trait ToF32: Sync {
    fn to_f32(&self) -> f32; 
 }
 
@@ -18,6 +20,12 @@ impl ToF32 for f32 {
     }
 }
 
+impl ToF32 for i8 {
+    fn to_f32(&self) -> f32 {
+        *self as f32
+    }
+}
+
 fn mul<T,U>(a:T,b:U) -> f32
 where T:Copy + Clone + Default + ToF32,
       U:Copy + Clone + Default + ToF32,
@@ -45,6 +53,16 @@ where T:Copy + Clone + Default + ToF32,
 
 // RoPE: Rotary Positional Embedding
 pub fn rope(y: &mut Tensor<f32>, start_pos: usize, theta: f32) {
+    rotate(y, |tok| (start_pos + tok) as f32, theta);
+}
+
+// Rotate already embedded vectors by `delta` positions. RoPE angles add up, so
+// shifting cached keys by a negative delta re-rotates them to earlier positions.
+pub fn rope_shift(y: &mut Tensor<f32>, delta: isize, theta: f32) {
+    rotate(y, |_| delta as f32, theta);
+}
+
+fn rotate(y: &mut Tensor<f32>, position: impl Fn(usize) -> f32, theta: f32) {
     let shape = y.shape();
     assert!(shape.len() == 3);
     let seq_len = shape[0];
@@ -52,12 +70,12 @@ pub fn rope(y: &mut Tensor<f32>, start_pos: usize, theta: f32) {
     let d = shape[2];
     let data = unsafe { y.data_mut() };
     for tok in 0..seq_len {
-        let pos = start_pos + tok;
+        let pos = position(tok);
         for head in 0..n_heads {
             for i in 0..d / 2 {
                 let a = data[tok * n_heads * d + head * d + i];
                 let b = data[tok * n_heads * d + head * d + i + d / 2];
-                let freq = pos as f32 / theta.powf((i * 2) as f32 / d as f32);
+                let freq = pos / theta.powf((i * 2) as f32 / d as f32);
                 let (sin, cos) = freq.sin_cos();
                 data[tok * n_heads * d + head * d + i] = a * cos - b * sin;
                 data[tok * n_heads * d + head * d + i + d / 2] = b * cos + a * sin;
@@ -138,7 +156,7 @@ pub fn swiglu(y: &mut Tensor<f32>, x: &Tensor<f32>) {
     for i in 0..len {
         let x_sigmoid = 1.0 / (1.0+ (-x_data[i]).exp());
         let silu = x_sigmoid * x_data[i];
-        y_data[i] = silu * y_data[i];
+        y_data[i] *= silu;
     }
 }
 
@@ -147,27 +165,28 @@ pub fn swiglu(y: &mut Tensor<f32>, x: &Tensor<f32>) {
 pub fn matmul_transb<T>(c: &mut Tensor<f32>, beta: f32, a: &Tensor<f32>, b: &Tensor<T>, alpha: f32)
 where T:Copy + Clone + Default + ToF32,
 {
-    let m = a.shape().get(0).unwrap();
+    let m = a.shape().first().unwrap();
     let k = a.shape().get(1).unwrap();
-    let n = b.shape().get(0).unwrap();
+    let n = b.shape().first().unwrap();
     let _k = b.shape().get(1).unwrap();
     assert!(k==_k);
-    assert!(c.shape().get(0).unwrap()==m);
+    assert!(c.shape().first().unwrap()==m);
     assert!(c.shape().get(1).unwrap()==n);
 
     let a_data = a.data();
     let b_data = b.data();
     let c_data = unsafe { c.data_mut() };
     let dim = *k;
-    for i in 0..*m {
-        for j in 0..*n{
-            let mut dot : f32 = 0.0;
-            for k in 0..dim {
-                dot += mul(a_data[i*dim+k], b_data[j*dim+k]);
-            }
-            c_data[i*n+j] = beta * c_data[i*n+j] + alpha * dot;
+    let n = *n;
+    // Every output element is independent, so they are spread over the rayon pool
+    c_data.par_iter_mut().with_min_len(16).enumerate().for_each(|(idx, c)| {
+        let (i, j) = (idx / n, idx % n);
+        let mut dot : f32 = 0.0;
+        for k in 0..dim {
+            dot += mul(a_data[i*dim+k], b_data[j*dim+k]);
         }
-    }
+        *c = beta * *c + alpha * dot;
+    });
 }
 
 // Dot product of two tensors (treated as vectors)
@@ -184,8 +203,15 @@ pub fn dot(x: &Tensor<f32>, y: &Tensor<f32>) -> f32 {
     sum
 }
 
+// log(softmax(x)) of a single row of logits
+pub fn log_softmax(x: &[f32]) -> Vec<f32> {
+    let max = x.iter().fold(f32::NEG_INFINITY, |a, b| a.max(*b));
+    let log_sum = x.iter().map(|v| (v - max).exp()).sum::<f32>().ln() + max;
+    x.iter().map(|v| v - log_sum).collect()
+}
+
 // Sample a index from a tensor (treated as a probability vector)
-pub fn random_sample(x: &Tensor<f32>, top_p: f32, top_k: u32, temperature: f32) -> u32 {
+pub fn random_sample(x: &Tensor<f32>, top_p: f32, top_k: u32, temperature: f32, rng: &mut impl Rng) -> u32 {
     assert!(x.shape()[x.shape().len() - 1] == x.size());
     if temperature <= 0. || top_k < 2 || top_p <= 0. {
         return x
@@ -222,7 +248,7 @@ pub fn random_sample(x: &Tensor<f32>, top_p: f32, top_k: u32, temperature: f32)
         #[inline]
         fn from((i, p): (usize, &f32)) -> Self {
             Self {
-                val: p.clone(),
+                val: *p,
                 tok: i as _,
             }
         }
@@ -244,13 +270,14 @@ pub fn random_sample(x: &Tensor<f32>, top_p: f32, top_k: u32, temperature: f32)
     // topk & topp & random
     let pk = logits[(top_k as usize).min(logits.len()) - 1].val;
     let pp = logits[logits.len() - 1].val * top_p;
-    let plimit = rand::random::<f32>() * f32::min(pk, pp);
+    let plimit = rng.gen::<f32>() * f32::min(pk, pp);
     // sample
     logits.iter().find(|p| p.val >= plimit).unwrap().tok
 }
 
 // Your implementation should at least pass the following tests:
 #[test]
+#[allow(clippy::useless_vec)]
 fn test_silu() {
     let mut y = Tensor::<f32>::new(vec![2., 3., 4.], &vec![1, 3]);
     let x = Tensor::<f32>::new(vec![1., 2., 3.], &vec![1, 3]);
@@ -262,6 +289,7 @@ fn test_silu() {
 }
 
 #[test]
+#[allow(clippy::useless_vec)]
 fn test_rms_norm() {
     let mut y = Tensor::<f32>::new(vec![1., 2., 3., 4.], &vec![2, 2]);
     let x = Tensor::<f32>::new(vec![1., 2., 3., 4.], &vec![2, 2]);
@@ -277,6 +305,16 @@ fn test_rms_norm() {
 }
 
 #[test]
+fn test_log_softmax() {
+    use crate::tensor::float_eq;
+    let y = log_softmax(&[1., 2., 3.]);
+    let expected = [-2.407606, -1.407606, -0.407606];
+    assert!(y.iter().zip(expected).all(|(a, b)| float_eq(a, &b, 1e-5)));
+    assert!(float_eq(&y.iter().map(|v| v.exp()).sum::<f32>(), &1., 1e-5));
+}
+
+#[test]
+#[allow(clippy::useless_vec)]
 fn test_matmul_transb() {
     let mut c = Tensor::<f32>::new(vec![1., 2., 3., 4.], &vec![2, 2]);
     let a = Tensor::<f32>::new(vec![1., 2., 3., 4., 5., 6.], &vec![2, 3]);
@@ -287,3 +325,15 @@ fn test_matmul_transb() {
         1e-3
     ));
 }
+
+#[test]
+fn test_rope_shift() {
+    let data = vec![0.3, -1.2, 0.7, 2.0, 1.5, 0.1, -0.4, 0.9];
+    let mut y = Tensor::<f32>::new(data.clone(), &[2, 1, 4]);
+    rope(&mut y, 7, 1e4);
+    // Moving both tokens 5 positions back is the same as embedding them at 2
+    rope_shift(&mut y, -5, 1e4);
+    let mut expected = Tensor::<f32>::new(data, &[2, 1, 4]);
+    rope(&mut expected, 2, 1e4);
+    assert!(y.close_to(&expected, 1e-4));
+}
@@ -2,9 +2,10 @@ use std::f32;
 
 use crate::config::LlamaConfigJson;
 use crate::tensor::Tensor;
-use safetensors::SafeTensors;
-use half::bf16;
+use safetensors::{Dtype, SafeTensors};
+use half::{bf16, f16};
 
+#[derive(Clone)]
 pub struct LLamaParams<T> {
     // token_id to embedding lookup table
     pub embedding_table: Tensor<T>, // (vocab_size, dim)
@@ -25,19 +26,30 @@ pub struct LLamaParams<T> {
 }
 
 pub trait Load: Sized {
+    // How the type is stored in safetensors files
+    const FILE_DTYPE: Dtype;
     fn from_le_bytes(bytes: &[u8]) -> Self; 
+    fn from_f32(x: f32) -> Self;
 }
 
 impl Load for f32 {
+    const FILE_DTYPE: Dtype = Dtype::F32;
     fn from_le_bytes(bytes: &[u8]) -> Self {
         f32::from_le_bytes(bytes.try_into().unwrap())
     }
+    fn from_f32(x: f32) -> Self {
+        x
+    }
 }
 
 impl Load for bf16 {
+    const FILE_DTYPE: Dtype = Dtype::BF16;
     fn from_le_bytes(bytes: &[u8]) -> Self {
         bf16::from_le_bytes(bytes.try_into().unwrap())
     }
+    fn from_f32(x: f32) -> Self {
+        bf16::from_f32(x)
+    }
 }
 
 impl<T> LLamaParams<T> 
@@ -46,14 +58,17 @@ where T: Default + Copy + Clone + Load
     pub fn from_safetensors(safetensor: &SafeTensors, config: &LlamaConfigJson) -> Self {
         //let names = safetensor.names();
         let get_tensor = |name: &str| -> Tensor<T> {
-            let tensor_view = safetensor.tensor(name).expect(&format!("tensor {name} not found"));
+            let tensor_view = safetensor.tensor(name).unwrap_or_else(|_| panic!("tensor {name} not found"));
             let shape = tensor_view.shape().to_vec();
-            let chunk_size = std::mem::size_of::<T>();
-            let data = tensor_view.data().chunks_exact(chunk_size)
-                .map(|chunk|{
-                    T::from_le_bytes(chunk)
-                })
-                .collect();
+            let bytes = tensor_view.data();
+            // Weights stored as another float type are converted on the way in
+            let data = match tensor_view.dtype() {
+                dtype if dtype == T::FILE_DTYPE => bytes.chunks_exact(std::mem::size_of::<T>()).map(T::from_le_bytes).collect(),
+                Dtype::F32 => bytes.chunks_exact(4).map(|c| T::from_f32(f32::from_le_bytes(c.try_into().unwrap()))).collect(),
+                Dtype::BF16 => bytes.chunks_exact(2).map(|c| T::from_f32(bf16::from_le_bytes(c.try_into().unwrap()).to_f32())).collect(),
+                Dtype::F16 => bytes.chunks_exact(2).map(|c| T::from_f32(f16::from_le_bytes(c.try_into().unwrap()).to_f32())).collect(),
+                dtype => panic!("tensor {name} has unsupported type {dtype:?}"),
+            };
             Tensor::new(data, &shape)
         };
         let n_layers = config.num_hidden_layers;
@@ -76,4 +91,9 @@ where T: Default + Copy + Clone + Load
             lm_head: get_tensor("lm_head.weight"),
         }
     }
+
+    pub fn tensors(&self) -> impl Iterator<Item = &Tensor<T>> {
+        let layers = [&self.rms_att_w, &self.wq, &self.wk, &self.wv, &self.wo, &self.rms_ffn_w, &self.w_up, &self.w_gate, &self.w_down];
+        [&self.embedding_table, &self.rms_out_w, &self.lm_head].into_iter().chain(layers.into_iter().flatten())
+    }
 }
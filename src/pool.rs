@@ -0,0 +1,130 @@
+use crate::metrics::{GaugeGuard, METRICS};
+use std::error::Error;
+use std::fmt;
+use std::future::Future;
+use std::sync::mpsc::{self, SyncSender, TrySendError};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+use tokio::sync::oneshot;
+
+type Job = Box<dyn FnOnce() + Send>;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum PoolError {
+    // Every worker is busy and the queue is full
+    QueueFull,
+    // The job waited in the queue for longer than allowed
+    Expired,
+    // The workers are gone
+    Stopped,
+}
+
+impl fmt::Display for PoolError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PoolError::QueueFull => write!(f, "the server is busy, try again later"),
+            PoolError::Expired => write!(f, "timed out waiting for a free compute slot"),
+            PoolError::Stopped => write!(f, "the compute pool has stopped"),
+        }
+    }
+}
+
+impl Error for PoolError {}
+
+// Runs inference on threads of its own, away from the HTTP workers. At most
+// `workers` jobs run at once, `queue_size` more may wait, and anything beyond
+// that is turned away rather than queued without bound.
+pub struct ComputePool {
+    jobs: SyncSender<Job>,
+    queue_timeout: Duration,
+}
+
+impl ComputePool {
+    pub fn new(workers: usize, queue_size: usize, queue_timeout: Duration) -> Self {
+        let (jobs, queue) = mpsc::sync_channel::<Job>(queue_size);
+        let queue = Arc::new(Mutex::new(queue));
+        for i in 0..workers.max(1) {
+            let queue = queue.clone();
+            thread::Builder::new()
+                .name(format!("compute-{i}"))
+                .spawn(move || loop {
+                    let job = queue.lock().unwrap().recv();
+                    match job {
+                        Ok(job) => job(),
+                        Err(_) => break,
+                    }
+                })
+                .unwrap();
+        }
+        ComputePool { jobs, queue_timeout }
+    }
+
+    // Queue `job`, which is told on start whether it waited too long.
+    pub fn spawn(&self, job: impl FnOnce(Result<(), PoolError>) + Send + 'static) -> Result<(), PoolError> {
+        let queued_at = Instant::now();
+        let queue_timeout = self.queue_timeout;
+        let waiting = GaugeGuard::add(&METRICS.queue_depth, 1);
+        let job: Job = Box::new(move || {
+            drop(waiting);
+            if queued_at.elapsed() > queue_timeout {
+                return job(Err(PoolError::Expired));
+            }
+            let _running = GaugeGuard::add(&METRICS.active_generations, 1);
+            job(Ok(()))
+        });
+        match self.jobs.try_send(job) {
+            Ok(()) => Ok(()),
+            Err(TrySendError::Full(_)) => Err(PoolError::QueueFull),
+            Err(TrySendError::Disconnected(_)) => Err(PoolError::Stopped),
+        }
+    }
+
+    // Queue `f` and return a future of its result. Jobs whose future has been
+    // dropped by the time a worker gets to them are skipped.
+    pub fn submit<R, F>(&self, f: F) -> Result<impl Future<Output = Result<R, PoolError>>, PoolError>
+    where
+        R: Send + 'static,
+        F: FnOnce() -> R + Send + 'static,
+    {
+        let (result, receiver) = oneshot::channel();
+        self.spawn(move |start| {
+            if !result.is_closed() {
+                let _ = result.send(start.map(|()| f()));
+            }
+        })?;
+        Ok(async move { receiver.await.unwrap_or(Err(PoolError::Stopped)) })
+    }
+}
+
+#[test]
+fn test_compute_pool() {
+    use std::sync::mpsc::channel;
+    let pool = ComputePool::new(1, 1, Duration::from_secs(60));
+    let (release, blocked) = channel::<()>();
+    let (started, wait_started) = channel::<()>();
+    // One job runs, one waits, the third is turned away
+    let running = pool.submit(move || {
+        started.send(()).unwrap();
+        blocked.recv().unwrap();
+        1
+    }).unwrap();
+    wait_started.recv().unwrap();
+    let waiting = pool.submit(|| 2).unwrap();
+    assert_eq!(pool.submit(|| 3).err(), Some(PoolError::QueueFull));
+    // Only the job still waiting for the worker counts as queued
+    assert_eq!(METRICS.queue_depth.get(), 1);
+    release.send(()).unwrap();
+    let rt = actix_web::rt::System::new();
+    assert_eq!(rt.block_on(running), Ok(1));
+    assert_eq!(rt.block_on(waiting), Ok(2));
+    assert_eq!(METRICS.queue_depth.get(), 0);
+
+    let pool = ComputePool::new(1, 4, Duration::from_millis(50));
+    let (release, blocked) = channel::<()>();
+    pool.spawn(move |_| blocked.recv().unwrap()).unwrap();
+    let expired = pool.submit(|| 4).unwrap();
+    thread::sleep(Duration::from_millis(100));
+    release.send(()).unwrap();
+    assert_eq!(rt.block_on(expired), Err(PoolError::Expired));
+}
@@ -0,0 +1,247 @@
+use crate::config::LlamaConfigJson;
+use crate::grammar::TokenTrie;
+use crate::model::Llama;
+use crate::settings::WeightDtype;
+use crate::template::ChatTemplate;
+use half::bf16;
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::error::Error;
+use std::fmt;
+use std::fs::File;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, RwLock};
+use std::thread;
+use tokenizers::Tokenizer;
+
+// Weights in the type they were loaded as.
+pub enum Weights {
+    F32(Llama<f32>),
+    BF16(Llama<bf16>),
+}
+
+// A model with everything needed to serve it, loaded once at startup.
+pub struct LoadedModel {
+    pub name: String,
+    pub config: LlamaConfigJson,
+    pub dtype: WeightDtype,
+    pub weights: Weights,
+    pub tokenizer: Tokenizer,
+    pub template: ChatTemplate,
+    // What grammars match generated tokens against
+    pub vocab: Arc<TokenTrie>,
+}
+
+impl LoadedModel {
+    // Weights load as `dtype`, or else as the model's own torch_dtype.
+    pub fn load(name: &str, dir: &Path, dtype: Option<WeightDtype>) -> Result<Self, Box<dyn Error>> {
+        let config_path = dir.join("config.json");
+        let config_file = File::open(&config_path).map_err(|e| format!("{}: {e}", config_path.display()))?;
+        let config: LlamaConfigJson = serde_json::from_reader(config_file)?;
+        let dtype = match dtype {
+            Some(dtype) => dtype,
+            None => match config.torch_dtype.as_ref() {
+                "bfloat16" => WeightDtype::BF16,
+                "float32" => WeightDtype::F32,
+                dtype => return Err(format!("unsupported torch_dtype {dtype}").into()),
+            },
+        };
+        let tokenizer = Tokenizer::from_file(dir.join("tokenizer.json")).map_err(|e| e.to_string())?;
+        let template = ChatTemplate::from_model_dir(dir)?;
+        let weights = match dtype {
+            WeightDtype::F32 => Weights::F32(Llama::from_safetensors(dir)),
+            WeightDtype::BF16 => Weights::BF16(Llama::from_safetensors(dir)),
+        };
+        let vocab = Arc::new(TokenTrie::new(vocab_pieces(&tokenizer)));
+        Ok(LoadedModel { name: name.to_string(), config, dtype, weights, tokenizer, template, vocab })
+    }
+
+    // Token ids of `text`. Prompts rendered by the chat template carry their
+    // special tokens already, so those are encoded without adding any.
+    pub fn encode(&self, text: &str, add_special_tokens: bool) -> Result<Vec<u32>, Box<dyn Error>> {
+        let encoding = self.tokenizer.encode(text, add_special_tokens).map_err(|e| e.to_string())?;
+        Ok(encoding.get_ids().to_vec())
+    }
+
+    pub fn decode(&self, ids: &[u32], skip_special_tokens: bool) -> Result<String, Box<dyn Error>> {
+        let vocab_size = self.tokenizer.get_vocab_size(true);
+        if let Some(id) = ids.iter().find(|&&id| id as usize >= vocab_size) {
+            return Err(format!("token id {id} is not in the vocabulary of {vocab_size}").into());
+        }
+        Ok(self.tokenizer.decode(ids, skip_special_tokens).map_err(|e| e.to_string())?)
+    }
+
+    pub fn info(&self) -> ModelInfo<'_> {
+        let (parameters, weight_bytes, context_length) = match &self.weights {
+            Weights::F32(llama) => (llama.parameter_count(self.config.tie_word_embeddings), llama.weight_bytes(), llama.max_seq_len()),
+            Weights::BF16(llama) => (llama.parameter_count(self.config.tie_word_embeddings), llama.weight_bytes(), llama.max_seq_len()),
+        };
+        ModelInfo { name: &self.name, dtype: self.dtype, parameters, weight_bytes, context_length, config: &self.config }
+    }
+}
+
+// The text each token contributes when it follows other text, which is what
+// grammar matching needs. Decoding a token on its own may drop a leading space,
+// so it is decoded after a plain anchor token and the anchor's text cut off.
+// Special tokens decode to nothing, as do bytes of an incomplete character.
+fn vocab_pieces(tokenizer: &Tokenizer) -> Vec<String> {
+    let anchor = *tokenizer.encode("a", false).unwrap().get_ids().last().unwrap();
+    let prefix = tokenizer.decode(&[anchor], true).unwrap();
+    (0..tokenizer.get_vocab_size(true) as u32)
+        .map(|id| {
+            let text = tokenizer.decode(&[anchor, id], true).unwrap();
+            match text.strip_prefix(&prefix) {
+                Some(piece) if !piece.contains('\u{FFFD}') => piece.to_string(),
+                _ => String::new(),
+            }
+        })
+        .collect()
+}
+
+// What /v1/models/{name} reports.
+#[derive(Serialize)]
+pub struct ModelInfo<'a> {
+    pub name: &'a str,
+    pub dtype: WeightDtype,
+    pub parameters: usize,
+    pub weight_bytes: usize,
+    pub context_length: usize,
+    pub config: &'a LlamaConfigJson,
+}
+
+#[derive(Clone)]
+enum ModelState {
+    Loading,
+    Ready(Arc<LoadedModel>),
+    Failed(String),
+}
+
+#[derive(Serialize, Debug, Clone, PartialEq)]
+#[serde(tag = "state", rename_all = "snake_case")]
+pub enum ModelStatus {
+    Loading,
+    Ready,
+    Failed { error: String },
+}
+
+#[derive(Debug, PartialEq)]
+pub enum ModelError {
+    NotFound(String),
+    Loading(String),
+    Failed(String, String),
+}
+
+impl fmt::Display for ModelError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ModelError::NotFound(name) => write!(f, "no model named '{name}'"),
+            ModelError::Loading(name) => write!(f, "model '{name}' is still loading"),
+            ModelError::Failed(name, error) => write!(f, "model '{name}' failed to load: {error}"),
+        }
+    }
+}
+
+impl Error for ModelError {}
+
+// The served models by name. They load on background threads so that the
+// server can answer health checks meanwhile.
+#[derive(Default)]
+pub struct ModelRegistry {
+    models: RwLock<BTreeMap<String, ModelState>>,
+}
+
+impl ModelRegistry {
+    pub fn load(self: &Arc<Self>, name: &str, dir: PathBuf, dtype: Option<WeightDtype>) {
+        self.models.write().unwrap().insert(name.to_string(), ModelState::Loading);
+        let registry = self.clone();
+        let name = name.to_string();
+        thread::spawn(move || {
+            // Loading panics on malformed weights, so it runs on a thread of its own
+            let loader = {
+                let name = name.clone();
+                thread::spawn(move || LoadedModel::load(&name, &dir, dtype).map_err(|e| e.to_string()))
+            };
+            let state = match loader.join() {
+                Ok(Ok(model)) => {
+                    tracing::info!(model = %name, dtype = ?model.dtype, "model loaded");
+                    ModelState::Ready(Arc::new(model))
+                }
+                Ok(Err(error)) => ModelState::Failed(error),
+                Err(panic) => ModelState::Failed(
+                    panic.downcast_ref::<String>().cloned()
+                        .or_else(|| panic.downcast_ref::<&str>().map(|s| s.to_string()))
+                        .unwrap_or_else(|| "loader panicked".into()),
+                ),
+            };
+            if let ModelState::Failed(error) = &state {
+                tracing::error!(model = %name, "failed to load model: {error}");
+            }
+            registry.models.write().unwrap().insert(name, state);
+        });
+    }
+
+    pub fn get(&self, name: &str) -> Result<Arc<LoadedModel>, ModelError> {
+        match self.models.read().unwrap().get(name) {
+            None => Err(ModelError::NotFound(name.to_string())),
+            Some(ModelState::Loading) => Err(ModelError::Loading(name.to_string())),
+            Some(ModelState::Failed(error)) => Err(ModelError::Failed(name.to_string(), error.clone())),
+            Some(ModelState::Ready(model)) => Ok(model.clone()),
+        }
+    }
+
+    pub fn status(&self) -> BTreeMap<String, ModelStatus> {
+        self.models.read().unwrap().iter()
+            .map(|(name, state)| {
+                let status = match state {
+                    ModelState::Loading => ModelStatus::Loading,
+                    ModelState::Ready(_) => ModelStatus::Ready,
+                    ModelState::Failed(error) => ModelStatus::Failed { error: error.clone() },
+                };
+                (name.clone(), status)
+            })
+            .collect()
+    }
+
+    // Ready once every model has loaded.
+    pub fn is_ready(&self) -> bool {
+        self.models.read().unwrap().values().all(|state| matches!(state, ModelState::Ready(_)))
+    }
+}
+
+#[test]
+fn test_model_registry() {
+    let project_dir = env!("CARGO_MANIFEST_DIR");
+    let registry = Arc::new(ModelRegistry::default());
+    registry.load("story", PathBuf::from(project_dir).join("models").join("story"), Some(WeightDtype::F32));
+    registry.load("missing", PathBuf::from(project_dir).join("models").join("missing"), None);
+    assert!(!registry.is_ready());
+    while registry.status().values().any(|status| *status == ModelStatus::Loading) {
+        thread::sleep(std::time::Duration::from_millis(10));
+    }
+    assert!(matches!(registry.status()["missing"], ModelStatus::Failed { .. }));
+    assert!(matches!(registry.get("missing"), Err(ModelError::Failed(..))));
+    assert_eq!(registry.get("chat").err(), Some(ModelError::NotFound("chat".into())));
+    assert!(!registry.is_ready());
+
+    let story = registry.get("story").unwrap();
+    let ids = story.encode("Once upon a time", false).unwrap();
+    assert_eq!(story.decode(&ids, true).unwrap(), "Once upon a time");
+    assert_eq!(story.encode("Once upon a time", true).unwrap().len(), ids.len() + 1);
+    assert!(story.decode(&[story.config.vocab_size as u32], true).is_err());
+    let info = story.info();
+    assert_eq!(info.dtype, WeightDtype::F32);
+    assert_eq!(info.context_length, story.config.max_position_embeddings);
+    assert_eq!(info.weight_bytes, 4 * (info.parameters + if story.config.tie_word_embeddings { story.config.vocab_size * story.config.hidden_size } else { 0 }));
+}
+
+#[test]
+fn test_vocab_pieces() {
+    let project_dir = env!("CARGO_MANIFEST_DIR");
+    let tokenizer = Tokenizer::from_file(PathBuf::from(project_dir).join("models").join("story").join("tokenizer.json")).unwrap();
+    let pieces = vocab_pieces(&tokenizer);
+    assert_eq!(pieces.len(), tokenizer.get_vocab_size(true));
+    let ids = tokenizer.encode("Once upon a time", false).unwrap().get_ids().to_vec();
+    let text: String = ids.iter().map(|&id| pieces[id as usize].as_str()).collect();
+    assert_eq!(text.trim_start(), "Once upon a time");
+    assert!(pieces[tokenizer.token_to_id("<|end_story|>").unwrap() as usize].is_empty());
+}
@@ -0,0 +1,523 @@
+use std::collections::HashMap;
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use serde::{Deserialize, Serialize};
+use crate::operators as OP;
+use crate::tensor::Tensor;
+
+// A step of the sampler chain: adjusts the logits of the next token given the
+// tokens seen so far (prompt followed by the generated ones).
+pub trait LogitProcessor: Send {
+    fn process(&mut self, logits: &mut [f32], history: &[u32]);
+}
+
+// Penalize every token occurring in the last `window` tokens: positive logits
+// are divided by `penalty` and negative ones multiplied, as in CTRL.
+pub struct RepetitionPenalty {
+    pub penalty: f32,
+    pub window: usize,
+}
+
+impl LogitProcessor for RepetitionPenalty {
+    fn process(&mut self, logits: &mut [f32], history: &[u32]) {
+        for (tok, _) in count_window(history, self.window) {
+            let logit = &mut logits[tok as usize];
+            *logit = if *logit > 0. { *logit / self.penalty } else { *logit * self.penalty };
+        }
+    }
+}
+
+// Subtract `frequency` once per occurrence and `presence` once per distinct
+// token in the last `window` tokens.
+pub struct FrequencyPresencePenalty {
+    pub frequency: f32,
+    pub presence: f32,
+    pub window: usize,
+}
+
+impl LogitProcessor for FrequencyPresencePenalty {
+    fn process(&mut self, logits: &mut [f32], history: &[u32]) {
+        for (tok, count) in count_window(history, self.window) {
+            logits[tok as usize] -= count as f32 * self.frequency + self.presence;
+        }
+    }
+}
+
+// Add a fixed bias to some tokens, -inf bans them.
+pub struct LogitBias(pub HashMap<u32, f32>);
+
+impl LogitProcessor for LogitBias {
+    fn process(&mut self, logits: &mut [f32], _history: &[u32]) {
+        for (&tok, &bias) in &self.0 {
+            if let Some(logit) = logits.get_mut(tok as usize) {
+                *logit += bias;
+            }
+        }
+    }
+}
+
+// Keep the tokens whose probability is at least `min_p` times the top one.
+pub struct MinP(pub f32);
+
+impl LogitProcessor for MinP {
+    fn process(&mut self, logits: &mut [f32], _history: &[u32]) {
+        let max = logits.iter().fold(f32::NEG_INFINITY, |a, b| a.max(*b));
+        let threshold = max + self.0.ln();
+        logits.iter_mut().filter(|l| **l < threshold).for_each(|l| *l = f32::NEG_INFINITY);
+    }
+}
+
+// Locally typical sampling: keep the tokens whose surprise is closest to the
+// entropy of the distribution, until they cover `mass` of the probability.
+pub struct Typical(pub f32);
+
+impl LogitProcessor for Typical {
+    fn process(&mut self, logits: &mut [f32], _history: &[u32]) {
+        let mut cands = candidates(logits);
+        let entropy = -cands.iter().map(|&(_, p)| p * p.ln()).sum::<f32>();
+        cands.sort_by(|a, b| (-a.1.ln() - entropy).abs().total_cmp(&(-b.1.ln() - entropy).abs()));
+        let mut cum = 0.;
+        let n_keep = cands.iter().take_while(|&&(_, p)| {
+            let covered = cum >= self.0;
+            cum += p;
+            !covered
+        }).count();
+        ban(logits, &cands[n_keep.max(1)..]);
+    }
+}
+
+// Tail free sampling: cut the sorted distribution where the magnitude of its
+// second derivative has accumulated to `z`.
+pub struct TailFree(pub f32);
+
+impl LogitProcessor for TailFree {
+    fn process(&mut self, logits: &mut [f32], _history: &[u32]) {
+        let cands = candidates(logits);
+        if cands.len() <= 2 {
+            return;
+        }
+        let d1 = cands.windows(2).map(|w| w[0].1 - w[1].1).collect::<Vec<_>>();
+        let d2 = d1.windows(2).map(|w| (w[0] - w[1]).abs()).collect::<Vec<_>>();
+        let sum = d2.iter().sum::<f32>();
+        if sum <= 0. {
+            return;
+        }
+        let mut cum = 0.;
+        let n_keep = d2.iter().enumerate()
+            .position(|(i, d)| {
+                cum += d / sum;
+                cum > self.0 && i >= 1
+            })
+            .unwrap_or(cands.len());
+        ban(logits, &cands[n_keep..]);
+    }
+}
+
+// Tokens with a non-zero probability and their probabilities, most likely first.
+fn candidates(logits: &[f32]) -> Vec<(u32, f32)> {
+    let probs = OP::log_softmax(logits).into_iter().map(f32::exp);
+    let mut cands = probs.enumerate()
+        .filter(|&(_, p)| p > 0.)
+        .map(|(i, p)| (i as u32, p))
+        .collect::<Vec<_>>();
+    cands.sort_by(|a, b| b.1.total_cmp(&a.1));
+    cands
+}
+
+fn ban(logits: &mut [f32], cands: &[(u32, f32)]) {
+    cands.iter().for_each(|&(tok, _)| logits[tok as usize] = f32::NEG_INFINITY);
+}
+
+// Mirostat keeps the surprise of the sampled text close to `tau` by adapting
+// the truncation after every step, so the sampler carries `mu` across steps.
+pub struct Mirostat {
+    pub version: u8,
+    pub tau: f32,
+    pub eta: f32,
+    mu: f32,
+}
+
+impl Mirostat {
+    pub fn new(version: u8, tau: f32, eta: f32) -> Self {
+        assert!(version == 1 || version == 2, "unknown mirostat version {version}");
+        Mirostat { version, tau, eta, mu: 2. * tau }
+    }
+
+    // Truncate the sorted candidates to the ones the current `mu` allows.
+    fn truncate(&self, cands: &mut Vec<(u32, f32)>) {
+        let n_keep = if self.version == 1 {
+            // Estimate the Zipf exponent from the top 100 tokens
+            let m = cands.len().min(100);
+            let (mut sum_ti_bi, mut sum_ti_sq) = (0., 0.);
+            for i in 0..m.saturating_sub(1) {
+                let t_i = ((i + 2) as f32 / (i + 1) as f32).ln();
+                let b_i = (cands[i].1 / cands[i + 1].1).ln();
+                sum_ti_bi += t_i * b_i;
+                sum_ti_sq += t_i * t_i;
+            }
+            let s_hat = sum_ti_bi / sum_ti_sq;
+            let epsilon_hat = s_hat - 1.;
+            let n = cands.len() as f32;
+            let k = ((epsilon_hat * 2f32.powf(self.mu)) / (1. - n.powf(-epsilon_hat))).powf(1. / s_hat);
+            if k.is_finite() { k as usize } else { cands.len() }
+        } else {
+            cands.iter().take_while(|&&(_, p)| -p.log2() <= self.mu).count()
+        };
+        cands.truncate(n_keep.max(1));
+    }
+
+    fn sample(&mut self, logits: &[f32], rng: &mut impl Rng) -> u32 {
+        let mut cands = candidates(logits);
+        self.truncate(&mut cands);
+        let total = cands.iter().map(|&(_, p)| p).sum::<f32>();
+        let plimit = rng.gen::<f32>() * total;
+        let mut cum = 0.;
+        let &(tok, p) = cands.iter()
+            .find(|&&(_, p)| { cum += p; cum >= plimit })
+            .unwrap_or(cands.last().unwrap());
+        let surprise = -(p / total).log2();
+        self.mu -= self.eta * (surprise - self.tau);
+        tok
+    }
+}
+
+fn count_window(history: &[u32], window: usize) -> HashMap<u32, usize> {
+    let mut counts = HashMap::new();
+    for &tok in &history[history.len().saturating_sub(window)..] {
+        *counts.entry(tok).or_insert(0) += 1;
+    }
+    counts
+}
+
+// Logit processors applied in order, followed by top-k/top-p sampling or,
+// if enabled, Mirostat. All randomness comes from a seeded RNG, so the same
+// seed, prompt and settings always produce the same tokens.
+pub struct Sampler {
+    processors: Vec<Box<dyn LogitProcessor>>,
+    mirostat: Option<Mirostat>,
+    top_p: f32,
+    top_k: u32,
+    temperature: f32,
+    seed: u64,
+    rng: StdRng,
+}
+
+impl Sampler {
+    // Seeded at random, see `with_seed` and `seed`.
+    pub fn new(top_p: f32, top_k: u32, temperature: f32) -> Self {
+        let seed = rand::random();
+        Sampler {
+            processors: Vec::new(),
+            mirostat: None,
+            top_p,
+            top_k,
+            temperature,
+            seed,
+            rng: StdRng::seed_from_u64(seed),
+        }
+    }
+
+    pub fn with_seed(mut self, seed: u64) -> Self {
+        self.seed = seed;
+        self.rng = StdRng::seed_from_u64(seed);
+        self
+    }
+
+    pub fn seed(&self) -> u64 {
+        self.seed
+    }
+
+    pub fn with(mut self, processor: impl LogitProcessor + 'static) -> Self {
+        self.processors.push(Box::new(processor));
+        self
+    }
+
+    pub fn with_mirostat(mut self, mirostat: Mirostat) -> Self {
+        self.mirostat = Some(mirostat);
+        self
+    }
+
+    pub fn sample(&mut self, logits: &Tensor<f32>, history: &[u32]) -> u32 {
+        let mut data = logits.data().to_vec();
+        for processor in self.processors.iter_mut() {
+            processor.process(&mut data, history);
+        }
+        if let Some(mirostat) = self.mirostat.as_mut() {
+            if self.temperature > 0. {
+                data.iter_mut().for_each(|l| *l /= self.temperature);
+            }
+            return mirostat.sample(&data, &mut self.rng);
+        }
+        let len = data.len();
+        OP::random_sample(&Tensor::new(data, &[len]), self.top_p, self.top_k, self.temperature, &mut self.rng)
+    }
+
+    // The probabilities `sample` draws the next token with. Mirostat adapts
+    // after every draw, so it has no fixed distribution to report.
+    pub fn distribution(&mut self, logits: &[f32], history: &[u32]) -> Vec<f32> {
+        assert!(self.mirostat.is_none(), "mirostat has no fixed distribution");
+        let mut data = logits.to_vec();
+        for processor in self.processors.iter_mut() {
+            processor.process(&mut data, history);
+        }
+        truncated_distribution(&data, self.top_p, self.top_k, self.temperature)
+    }
+
+    pub fn rng(&mut self) -> &mut StdRng {
+        &mut self.rng
+    }
+}
+
+// The distribution `OP::random_sample` draws from: the softmax at
+// `temperature` of the `top_k` most likely tokens, cut where the cumulative
+// probability reaches `top_p`, or one-hot on the most likely token.
+fn truncated_distribution(logits: &[f32], top_p: f32, top_k: u32, temperature: f32) -> Vec<f32> {
+    let mut probs = vec![0.; logits.len()];
+    if temperature <= 0. || top_k < 2 || top_p <= 0. {
+        let argmax = logits.iter().enumerate().max_by(|(_, a), (_, b)| a.partial_cmp(b).unwrap()).unwrap().0;
+        probs[argmax] = 1.;
+        return probs;
+    }
+    let mut order = (0..logits.len()).collect::<Vec<_>>();
+    order.sort_unstable_by(|&a, &b| logits[b].total_cmp(&logits[a]).then(a.cmp(&b)));
+    let max = logits[order[0]];
+    let weights = order.iter().map(|&i| ((logits[i] - max) / temperature).exp()).collect::<Vec<_>>();
+    let top_k = weights[..(top_k as usize).min(weights.len())].iter().sum::<f32>();
+    let limit = top_k.min(weights.iter().sum::<f32>() * top_p);
+    let mut cum = 0.;
+    for (&i, &w) in order.iter().zip(&weights) {
+        if cum >= limit {
+            break;
+        }
+        probs[i] = w.min(limit - cum) / limit;
+        cum += w;
+    }
+    probs
+}
+
+// Per-request sampling settings, see `SamplingParams::sampler`.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct SamplingParams {
+    #[serde(default = "default_top_p")]
+    pub top_p: f32,
+    #[serde(default = "default_top_k")]
+    pub top_k: u32,
+    #[serde(default = "default_temperature")]
+    pub temperature: f32,
+    #[serde(default)]
+    pub repetition_penalty: Option<f32>,
+    #[serde(default = "default_penalty_window")]
+    pub penalty_window: usize,
+    #[serde(default)]
+    pub frequency_penalty: f32,
+    #[serde(default)]
+    pub presence_penalty: f32,
+    #[serde(default)]
+    pub logit_bias: HashMap<u32, f32>,
+    #[serde(default)]
+    pub min_p: Option<f32>,
+    #[serde(default)]
+    pub typical_p: Option<f32>,
+    #[serde(default)]
+    pub tfs_z: Option<f32>,
+    // 0 disables Mirostat, 1 and 2 select the version
+    #[serde(default)]
+    pub mirostat: u8,
+    #[serde(default = "default_mirostat_tau")]
+    pub mirostat_tau: f32,
+    #[serde(default = "default_mirostat_eta")]
+    pub mirostat_eta: f32,
+    // Picked at random when missing; the one used is reported back
+    #[serde(default)]
+    pub seed: Option<u64>,
+}
+
+impl Default for SamplingParams {
+    fn default() -> Self {
+        serde_json::from_str("{}").unwrap()
+    }
+}
+
+impl SamplingParams {
+    pub fn sampler(&self) -> Sampler {
+        let mut sampler = Sampler::new(self.top_p, self.top_k, self.temperature);
+        if let Some(seed) = self.seed {
+            sampler = sampler.with_seed(seed);
+        }
+        if let Some(penalty) = self.repetition_penalty {
+            sampler = sampler.with(RepetitionPenalty { penalty, window: self.penalty_window });
+        }
+        if self.frequency_penalty != 0. || self.presence_penalty != 0. {
+            sampler = sampler.with(FrequencyPresencePenalty {
+                frequency: self.frequency_penalty,
+                presence: self.presence_penalty,
+                window: self.penalty_window,
+            });
+        }
+        if !self.logit_bias.is_empty() {
+            sampler = sampler.with(LogitBias(self.logit_bias.clone()));
+        }
+        if let Some(z) = self.tfs_z {
+            sampler = sampler.with(TailFree(z));
+        }
+        if let Some(mass) = self.typical_p {
+            sampler = sampler.with(Typical(mass));
+        }
+        if let Some(min_p) = self.min_p {
+            sampler = sampler.with(MinP(min_p));
+        }
+        if self.mirostat != 0 {
+            sampler = sampler.with_mirostat(Mirostat::new(self.mirostat, self.mirostat_tau, self.mirostat_eta));
+        }
+        sampler
+    }
+}
+
+#[inline(always)]
+const fn default_top_p() -> f32 {
+    0.8
+}
+
+#[inline(always)]
+const fn default_top_k() -> u32 {
+    30
+}
+
+#[inline(always)]
+const fn default_temperature() -> f32 {
+    1.
+}
+
+#[inline(always)]
+const fn default_penalty_window() -> usize {
+    64
+}
+
+#[inline(always)]
+const fn default_mirostat_tau() -> f32 {
+    5.
+}
+
+#[inline(always)]
+const fn default_mirostat_eta() -> f32 {
+    0.1
+}
+
+#[test]
+fn test_repetition_penalty() {
+    let mut logits = vec![2., -2., 1., 4.];
+    RepetitionPenalty { penalty: 2., window: 2 }.process(&mut logits, &[3, 0, 1]);
+    assert_eq!(logits, vec![1., -4., 1., 4.]);
+}
+
+#[test]
+fn test_frequency_presence_penalty() {
+    let mut logits = vec![0., 0., 0.];
+    FrequencyPresencePenalty { frequency: 0.5, presence: 1., window: 8 }.process(&mut logits, &[1, 1, 1, 2]);
+    assert_eq!(logits, vec![0., -2.5, -1.5]);
+}
+
+#[test]
+fn test_sampler_chain() {
+    let params: SamplingParams = serde_json::from_str(r#"{"top_k": 1, "logit_bias": {"2": -100}, "repetition_penalty": 10}"#).unwrap();
+    let mut sampler = params.sampler();
+    let logits = Tensor::new(vec![1., 3., 5., 4.], &[4]);
+    // token 2 is banned by the bias, token 3 by the penalty
+    assert_eq!(sampler.sample(&logits, &[3]), 1);
+    assert_eq!(SamplingParams::default().top_k, 30);
+}
+
+// Tokens a processor leaves possible to sample
+#[cfg(test)]
+fn kept(processor: &mut impl LogitProcessor, probs: &[f32]) -> Vec<u32> {
+    let mut logits = probs.iter().map(|p| p.ln()).collect::<Vec<_>>();
+    processor.process(&mut logits, &[]);
+    (0..probs.len() as u32).filter(|&i| logits[i as usize].is_finite()).collect()
+}
+
+#[test]
+fn test_min_p() {
+    assert_eq!(kept(&mut MinP(0.2), &[0.15, 0.5, 0.05, 0.3]), vec![0, 1, 3]);
+    assert_eq!(kept(&mut MinP(0.7), &[0.15, 0.5, 0.05, 0.3]), vec![1]);
+}
+
+#[test]
+fn test_typical() {
+    // entropy is 1.28 nats, surprises are 0.92, 1.20, 1.61 and 2.30
+    assert_eq!(kept(&mut Typical(0.45), &[0.4, 0.3, 0.2, 0.1]), vec![1, 2]);
+    assert_eq!(kept(&mut Typical(0.2), &[0.4, 0.3, 0.2, 0.1]), vec![1]);
+    assert_eq!(kept(&mut Typical(1.0), &[0.4, 0.3, 0.2, 0.1]), vec![0, 1, 2, 3]);
+}
+
+#[test]
+fn test_tail_free() {
+    // normalized second derivatives are 0.714, 0.095 and 0.190
+    let probs = [0.5, 0.25, 0.15, 0.07, 0.03];
+    assert_eq!(kept(&mut TailFree(0.5), &probs), vec![0]);
+    assert_eq!(kept(&mut TailFree(0.9), &probs), vec![0, 1]);
+    assert_eq!(kept(&mut TailFree(1.5), &probs), vec![0, 1, 2, 3, 4]);
+}
+
+#[test]
+fn test_mirostat() {
+    // Zipf distribution with exponent 1.2 over 10 tokens
+    let weights = (1..=10).map(|i| 1. / (i as f32).powf(1.2)).collect::<Vec<_>>();
+    let sum = weights.iter().sum::<f32>();
+    let cands = weights.iter().enumerate().map(|(i, w)| (i as u32, w / sum)).collect::<Vec<_>>();
+
+    let mut v1 = Mirostat::new(1, 2., 0.1); // mu = 4 gives k = 6.05
+    let mut truncated = cands.clone();
+    v1.truncate(&mut truncated);
+    assert_eq!(truncated.len(), 6);
+
+    // surprises are 1.30, 2.50, 3.21, ... bits
+    let mut v2 = Mirostat::new(2, 1.5, 0.1);
+    let mut truncated = cands.clone();
+    v2.truncate(&mut truncated);
+    assert_eq!(truncated.len(), 2);
+
+    // Only one candidate is left: it is always drawn and has no surprise, so
+    // mu moves up by eta * tau
+    v2.mu = 1.;
+    let logits = cands.iter().map(|(_, p)| p.ln()).collect::<Vec<_>>();
+    let rng = &mut StdRng::seed_from_u64(0);
+    assert_eq!(v2.sample(&logits, rng), 0);
+    assert!((v2.mu - 1.15).abs() < 1e-6);
+    v1.mu = 0.;
+    assert_eq!(v1.sample(&logits, rng), 0);
+}
+
+#[test]
+fn test_seeded_sampling() {
+    let logits = Tensor::new((0..64).map(|i| (i % 7) as f32 * 0.3).collect(), &[64]);
+    let draw = |sampler: &mut Sampler| (0..32).map(|_| sampler.sample(&logits, &[])).collect::<Vec<_>>();
+    let params: SamplingParams = serde_json::from_str(r#"{"seed": 7, "top_k": 64, "top_p": 1}"#).unwrap();
+    let first = draw(&mut params.sampler());
+    assert_eq!(first, draw(&mut params.sampler()));
+    assert_eq!(params.sampler().seed(), 7);
+    assert_ne!(first, draw(&mut params.sampler().with_seed(8)));
+
+    let mut random = SamplingParams::default().sampler();
+    let seed = random.seed();
+    assert_eq!(draw(&mut random), draw(&mut Sampler::new(0.8, 30, 1.).with_seed(seed)));
+}
+
+#[test]
+fn test_distribution() {
+    let logits = Tensor::new(vec![0.5, 2., -1., 1.5, 1., 0.], &[6]);
+    let params: SamplingParams = serde_json::from_str(r#"{"seed": 3, "top_k": 4, "top_p": 0.9, "temperature": 0.7}"#).unwrap();
+    let mut sampler = params.sampler();
+    let probs = sampler.distribution(logits.data(), &[]);
+    assert!((probs.iter().sum::<f32>() - 1.).abs() < 1e-5);
+    assert_eq!(probs[2], 0.);
+    // Sampling draws the tokens as often as the distribution says
+    let n = 40000;
+    let mut counts = [0; 6];
+    (0..n).for_each(|_| counts[sampler.sample(&logits, &[]) as usize] += 1);
+    for (count, p) in counts.iter().zip(&probs) {
+        assert!((*count as f32 / n as f32 - p).abs() < 0.01);
+    }
+
+    let mut greedy = Sampler::new(0.8, 1, 1.);
+    assert_eq!(greedy.distribution(logits.data(), &[]), [0., 1., 0., 0., 0., 0.]);
+}
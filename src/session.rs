@@ -0,0 +1,599 @@
+use crate::template::Message;
+use dashmap::DashMap;
+use serde::{Deserialize, Serialize};
+use std::fs::{File, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+// Seconds since the Unix epoch.
+pub fn now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs())
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct SessionMessage {
+    pub role: String,
+    pub content: String,
+    pub token_ids: Vec<u32>,
+    pub created_at: u64,
+}
+
+impl SessionMessage {
+    pub fn new(role: &str, content: impl Into<String>, token_ids: Vec<u32>) -> Self {
+        SessionMessage { role: role.to_string(), content: content.into(), token_ids, created_at: now() }
+    }
+
+    pub fn message(&self) -> Message {
+        Message::new(&self.role, self.content.as_str())
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Session {
+    pub id: String,
+    #[serde(default)]
+    pub user_id: Option<String>,
+    // Name of the API key that started the session, when authentication is on
+    #[serde(default)]
+    pub owner: Option<String>,
+    pub messages: Vec<SessionMessage>,
+    pub created_at: u64,
+    pub updated_at: u64,
+}
+
+impl Session {
+    fn new(id: &str, user_id: Option<&str>, owner: Option<&str>) -> Self {
+        let now = now();
+        Session {
+            id: id.to_string(),
+            user_id: user_id.map(str::to_string),
+            owner: owner.map(str::to_string),
+            messages: Vec::new(),
+            created_at: now,
+            updated_at: now,
+        }
+    }
+
+    // The last user message and the reply to it, if the session ends in one.
+    pub fn last_turn(&self) -> Option<(&SessionMessage, &SessionMessage)> {
+        match self.messages.as_slice() {
+            [.., user, assistant] if user.role == "user" && assistant.role == "assistant" => Some((user, assistant)),
+            _ => None,
+        }
+    }
+}
+
+// The API key a turn comes with, when authentication is on.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Owner<'a> {
+    pub name: &'a str,
+    // May use the sessions of every key
+    pub admin: bool,
+}
+
+impl Owner<'_> {
+    pub fn may_access(&self, owner: Option<&str>) -> bool {
+        self.admin || owner == Some(self.name)
+    }
+}
+
+#[derive(Serialize, Debug, PartialEq)]
+pub struct SessionSummary {
+    pub id: String,
+    pub user_id: Option<String>,
+    pub owner: Option<String>,
+    pub messages: usize,
+    pub tokens: usize,
+    pub created_at: u64,
+    pub updated_at: u64,
+}
+
+// Where sessions are kept, see `open_store`.
+#[derive(Deserialize, Debug, Clone, Default, PartialEq)]
+#[serde(tag = "backend", rename_all = "snake_case")]
+pub enum StoreBackend {
+    #[default]
+    Memory,
+    // Append-only log of session changes, compacted when opened
+    Jsonl { path: PathBuf },
+}
+
+#[derive(Deserialize, Debug, Clone, Default, PartialEq)]
+pub struct SessionConfig {
+    #[serde(default)]
+    pub store: StoreBackend,
+    // Sessions not updated for this long are dropped
+    #[serde(default)]
+    pub ttl_secs: Option<u64>,
+    // Starting one more session for a user drops their least recently updated
+    // one started with the same API key
+    #[serde(default)]
+    pub max_sessions_per_user: Option<usize>,
+}
+
+// Conversations by session id, each a list of user and assistant messages.
+// System messages come with every request and are not stored.
+pub trait SessionStore: Send + Sync {
+    fn list(&self) -> Vec<SessionSummary>;
+    fn get(&self, id: &str) -> Option<Session>;
+    fn delete(&self, id: &str) -> io::Result<bool>;
+    // Append a user message and its reply, creating the session for `owner`
+    // if needed. Fails with `PermissionDenied` if `owner` may not use the
+    // session.
+    fn push_turn(&self, id: &str, user_id: Option<&str>, owner: Option<Owner>, user: SessionMessage, assistant: SessionMessage) -> io::Result<()>;
+    // Remove the last user message and its reply.
+    fn pop_turn(&self, id: &str) -> io::Result<Option<(SessionMessage, SessionMessage)>>;
+    // Swap the reply of the last turn for a regenerated one.
+    fn replace_reply(&self, id: &str, assistant: SessionMessage) -> io::Result<bool>;
+
+    // The messages to render before a new user message.
+    fn history(&self, id: &str) -> Vec<Message> {
+        self.get(id).map(|s| s.messages.iter().map(SessionMessage::message).collect()).unwrap_or_default()
+    }
+}
+
+pub fn open_store(config: &SessionConfig) -> io::Result<Arc<dyn SessionStore>> {
+    let memory = MemoryStore::new(config.ttl_secs, config.max_sessions_per_user);
+    Ok(match &config.store {
+        StoreBackend::Memory => Arc::new(memory),
+        StoreBackend::Jsonl { path } => Arc::new(JsonlStore::open(path, memory)?),
+    })
+}
+
+pub struct MemoryStore {
+    sessions: DashMap<String, Session>,
+    ttl_secs: Option<u64>,
+    max_sessions_per_user: Option<usize>,
+    // Held while adding a turn, so that two new sessions of a user cannot
+    // both pass the check against `max_sessions_per_user`
+    adding: Mutex<()>,
+}
+
+impl MemoryStore {
+    pub fn new(ttl_secs: Option<u64>, max_sessions_per_user: Option<usize>) -> Self {
+        MemoryStore { sessions: DashMap::new(), ttl_secs, max_sessions_per_user, adding: Mutex::new(()) }
+    }
+
+    fn is_expired(&self, session: &Session, now: u64) -> bool {
+        self.ttl_secs.is_some_and(|ttl| now.saturating_sub(session.updated_at) > ttl)
+    }
+
+    // Drop the expired sessions and return their ids.
+    fn purge_expired(&self) -> Vec<String> {
+        let now = now();
+        let mut expired = Vec::new();
+        self.sessions.retain(|id, s| {
+            let keep = !self.is_expired(s, now);
+            if !keep {
+                expired.push(id.clone());
+            }
+            keep
+        });
+        expired
+    }
+
+    // The live session `id`, and its id if it was dropped for having expired.
+    fn lookup(&self, id: &str) -> (Option<Session>, Vec<String>) {
+        let Some(session) = self.sessions.get(id).map(|s| s.clone()) else {
+            return (None, Vec::new());
+        };
+        if self.is_expired(&session, now()) {
+            self.sessions.remove(id);
+            return (None, vec![id.to_string()]);
+        }
+        (Some(session), Vec::new())
+    }
+
+    // Summaries of the live sessions, most recently updated first, and the ids
+    // of the expired ones dropped on the way.
+    fn summaries(&self) -> (Vec<SessionSummary>, Vec<String>) {
+        let expired = self.purge_expired();
+        let mut summaries: Vec<SessionSummary> = self
+            .sessions
+            .iter()
+            .map(|s| SessionSummary {
+                id: s.id.clone(),
+                user_id: s.user_id.clone(),
+                owner: s.owner.clone(),
+                messages: s.messages.len(),
+                tokens: s.messages.iter().map(|m| m.token_ids.len()).sum(),
+                created_at: s.created_at,
+                updated_at: s.updated_at,
+            })
+            .collect();
+        summaries.sort_by(|a, b| b.updated_at.cmp(&a.updated_at).then_with(|| a.id.cmp(&b.id)));
+        (summaries, expired)
+    }
+
+    fn put(&self, session: Session) {
+        self.sessions.insert(session.id.clone(), session);
+    }
+
+    fn remove(&self, id: &str) -> bool {
+        self.sessions.remove(id).is_some()
+    }
+
+    // Apply `f` to a live session and return its result with the new state.
+    // An expired session is left for `purge_expired` to drop.
+    fn update<R>(&self, id: &str, f: impl FnOnce(&mut Session) -> Option<R>) -> Option<(R, Session)> {
+        let mut session = self.sessions.get_mut(id)?;
+        if self.is_expired(&session, now()) {
+            return None;
+        }
+        let result = f(&mut session)?;
+        session.updated_at = now();
+        Some((result, session.clone()))
+    }
+
+    // Add a turn; returns when the session was updated and the ids of the
+    // sessions dropped, for having expired or to keep its user within
+    // `max_sessions_per_user`. Checking the owner under `adding` keeps
+    // another key from starting the session in the meantime.
+    fn add_turn(&self, id: &str, user_id: Option<&str>, owner: Option<Owner>, user: SessionMessage, assistant: SessionMessage) -> io::Result<(u64, Vec<String>)> {
+        let _adding = self.adding.lock().unwrap();
+        if let (Some(owner), Some(session)) = (owner, self.sessions.get(id)) {
+            if !self.is_expired(&session, now()) && !owner.may_access(session.owner.as_deref()) {
+                return Err(io::Error::new(io::ErrorKind::PermissionDenied, format!("session {id} belongs to another key")));
+            }
+        }
+        let owner = owner.map(|owner| owner.name);
+        let mut evicted = self.purge_expired();
+        if let (Some(user_id), Some(max)) = (user_id, self.max_sessions_per_user) {
+            if !self.sessions.contains_key(id) {
+                // The user id comes from the client, so only sessions of the
+                // same key count; another key cannot evict them by naming the user
+                let mut owned: Vec<(u64, String)> = self
+                    .sessions
+                    .iter()
+                    .filter(|s| s.user_id.as_deref() == Some(user_id) && s.owner.as_deref() == owner)
+                    .map(|s| (s.updated_at, s.id.clone()))
+                    .collect();
+                owned.sort();
+                let n_evict = (owned.len() + 1).saturating_sub(max.max(1));
+                for (_, id) in owned.into_iter().take(n_evict) {
+                    self.sessions.remove(&id);
+                    evicted.push(id);
+                }
+            }
+        }
+        let mut session = self.sessions.entry(id.to_string()).or_insert_with(|| Session::new(id, user_id, owner));
+        session.messages.push(user);
+        session.messages.push(assistant);
+        session.updated_at = now();
+        Ok((session.updated_at, evicted))
+    }
+
+    // Add a turn read back from a log, as it was added at `at`.
+    fn replay_turn(&self, id: &str, user_id: Option<&str>, owner: Option<&str>, user: SessionMessage, assistant: SessionMessage, at: u64) {
+        let mut session = self.sessions.entry(id.to_string()).or_insert_with(|| Session { created_at: at, ..Session::new(id, user_id, owner) });
+        session.messages.push(user);
+        session.messages.push(assistant);
+        session.updated_at = at;
+    }
+
+    fn remove_turn(&self, id: &str) -> Option<((SessionMessage, SessionMessage), Session)> {
+        self.update(id, |session| {
+            session.last_turn()?;
+            let assistant = session.messages.pop().unwrap();
+            let user = session.messages.pop().unwrap();
+            Some((user, assistant))
+        })
+    }
+
+    fn swap_reply(&self, id: &str, assistant: SessionMessage) -> Option<Session> {
+        self.update(id, |session| {
+            session.last_turn()?;
+            *session.messages.last_mut().unwrap() = assistant;
+            Some(())
+        })
+        .map(|(_, session)| session)
+    }
+}
+
+impl SessionStore for MemoryStore {
+    fn list(&self) -> Vec<SessionSummary> {
+        self.summaries().0
+    }
+
+    fn get(&self, id: &str) -> Option<Session> {
+        self.lookup(id).0
+    }
+
+    fn delete(&self, id: &str) -> io::Result<bool> {
+        Ok(self.remove(id))
+    }
+
+    fn push_turn(&self, id: &str, user_id: Option<&str>, owner: Option<Owner>, user: SessionMessage, assistant: SessionMessage) -> io::Result<()> {
+        self.add_turn(id, user_id, owner, user, assistant).map(|_| ())
+    }
+
+    fn pop_turn(&self, id: &str) -> io::Result<Option<(SessionMessage, SessionMessage)>> {
+        Ok(self.remove_turn(id).map(|(turn, _)| turn))
+    }
+
+    fn replace_reply(&self, id: &str, assistant: SessionMessage) -> io::Result<bool> {
+        Ok(self.swap_reply(id, assistant).is_some())
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum Record {
+    Put(Session),
+    Delete(String),
+    // A turn added to a session at `at`, creating it if needed
+    Turn {
+        id: String,
+        user_id: Option<String>,
+        #[serde(default)]
+        owner: Option<String>,
+        user: SessionMessage,
+        assistant: SessionMessage,
+        at: u64,
+    },
+}
+
+// A `MemoryStore` that logs every change to a JSONL file, one record per
+// line, and replays it on startup. New turns are logged on their own rather
+// than with the whole session. Opening the store rewrites the log with just
+// the sessions still alive, so it does not grow without bound.
+pub struct JsonlStore {
+    memory: MemoryStore,
+    // Held while changing a session so that records land in the same order
+    // as the changes they describe
+    log: Mutex<File>,
+}
+
+impl JsonlStore {
+    pub fn open(path: impl AsRef<Path>, memory: MemoryStore) -> io::Result<Self> {
+        let path = path.as_ref();
+        if path.exists() {
+            let text = std::fs::read_to_string(path)?;
+            let lines: Vec<&str> = text.lines().filter(|l| !l.trim().is_empty()).collect();
+            for (i, line) in lines.iter().enumerate() {
+                match serde_json::from_str(line) {
+                    Ok(Record::Put(session)) => memory.put(session),
+                    Ok(Record::Delete(id)) => {
+                        memory.remove(&id);
+                    }
+                    Ok(Record::Turn { id, user_id, owner, user, assistant, at }) => {
+                        memory.replay_turn(&id, user_id.as_deref(), owner.as_deref(), user, assistant, at);
+                    }
+                    // A torn write at the end of the log from a crash
+                    Err(_) if i + 1 == lines.len() => {}
+                    Err(e) => return Err(io::Error::new(io::ErrorKind::InvalidData, format!("{}:{}: {e}", path.display(), i + 1))),
+                }
+            }
+            memory.purge_expired();
+        }
+        let compacted = path.with_extension("jsonl.tmp");
+        let mut file = File::create(&compacted)?;
+        for session in memory.sessions.iter() {
+            writeln!(file, "{}", serde_json::to_string(&Record::Put(session.clone()))?)?;
+        }
+        file.sync_all()?;
+        std::fs::rename(&compacted, path)?;
+        let log = OpenOptions::new().append(true).open(path)?;
+        Ok(JsonlStore { memory, log: Mutex::new(log) })
+    }
+
+    fn append(log: &mut File, records: &[Record]) -> io::Result<()> {
+        let mut lines = String::new();
+        for record in records {
+            lines += &serde_json::to_string(record)?;
+            lines.push('\n');
+        }
+        log.write_all(lines.as_bytes())?;
+        log.flush()
+    }
+
+    // Log the sessions a read found expired, so that a later session with the
+    // same id does not pick up their turns when the log is replayed.
+    fn log_expired(log: &mut File, expired: Vec<String>) {
+        if expired.is_empty() {
+            return;
+        }
+        let records: Vec<Record> = expired.into_iter().map(Record::Delete).collect();
+        if let Err(e) = Self::append(log, &records) {
+            tracing::warn!("failed to log expired sessions: {e}");
+        }
+    }
+}
+
+impl SessionStore for JsonlStore {
+    fn list(&self) -> Vec<SessionSummary> {
+        let mut log = self.log.lock().unwrap();
+        let (summaries, expired) = self.memory.summaries();
+        Self::log_expired(&mut log, expired);
+        summaries
+    }
+
+    fn get(&self, id: &str) -> Option<Session> {
+        let mut log = self.log.lock().unwrap();
+        let (session, expired) = self.memory.lookup(id);
+        Self::log_expired(&mut log, expired);
+        session
+    }
+
+    fn delete(&self, id: &str) -> io::Result<bool> {
+        let mut log = self.log.lock().unwrap();
+        if !self.memory.remove(id) {
+            return Ok(false);
+        }
+        Self::append(&mut log, &[Record::Delete(id.to_string())])?;
+        Ok(true)
+    }
+
+    fn push_turn(&self, id: &str, user_id: Option<&str>, owner: Option<Owner>, user: SessionMessage, assistant: SessionMessage) -> io::Result<()> {
+        let mut log = self.log.lock().unwrap();
+        let (at, evicted) = self.memory.add_turn(id, user_id, owner, user.clone(), assistant.clone())?;
+        let mut records: Vec<Record> = evicted.into_iter().map(Record::Delete).collect();
+        records.push(Record::Turn {
+            id: id.to_string(),
+            user_id: user_id.map(str::to_string),
+            owner: owner.map(|owner| owner.name.to_string()),
+            user,
+            assistant,
+            at,
+        });
+        Self::append(&mut log, &records)
+    }
+
+    fn pop_turn(&self, id: &str) -> io::Result<Option<(SessionMessage, SessionMessage)>> {
+        let mut log = self.log.lock().unwrap();
+        let Some((turn, session)) = self.memory.remove_turn(id) else {
+            return Ok(None);
+        };
+        Self::append(&mut log, &[Record::Put(session)])?;
+        Ok(Some(turn))
+    }
+
+    fn replace_reply(&self, id: &str, assistant: SessionMessage) -> io::Result<bool> {
+        let mut log = self.log.lock().unwrap();
+        let Some(session) = self.memory.swap_reply(id, assistant) else {
+            return Ok(false);
+        };
+        Self::append(&mut log, &[Record::Put(session)])?;
+        Ok(true)
+    }
+}
+
+#[cfg(test)]
+fn owner(name: &str) -> Option<Owner<'_>> {
+    Some(Owner { name, admin: false })
+}
+
+#[cfg(test)]
+fn turn(text: &str) -> (SessionMessage, SessionMessage) {
+    (SessionMessage::new("user", text, vec![1, 2]), SessionMessage::new("assistant", text, vec![3]))
+}
+
+#[test]
+fn test_session_store() {
+    let store = MemoryStore::new(None, None);
+    assert!(store.history("a").is_empty());
+    store.push_turn("a", None, None, SessionMessage::new("user", "hi", vec![1, 2]), SessionMessage::new("assistant", "hello", vec![3])).unwrap();
+    store.push_turn("a", None, None, SessionMessage::new("user", "bye", vec![4]), SessionMessage::new("assistant", "see you", vec![5, 6])).unwrap();
+    store.push_turn("b", None, None, SessionMessage::new("user", "x", vec![7]), SessionMessage::new("assistant", "y", vec![8])).unwrap();
+    assert_eq!(store.history("a")[2], Message::new("user", "bye"));
+
+    let summaries = store.list();
+    assert_eq!(summaries.len(), 2);
+    let a = summaries.iter().find(|s| s.id == "a").unwrap();
+    assert_eq!((a.messages, a.tokens), (4, 6));
+
+    assert!(store.replace_reply("a", SessionMessage::new("assistant", "later", vec![9])).unwrap());
+    assert_eq!(store.get("a").unwrap().last_turn().unwrap().1.content, "later");
+    let (user, assistant) = store.pop_turn("a").unwrap().unwrap();
+    assert_eq!((user.content.as_str(), assistant.content.as_str()), ("bye", "later"));
+    assert_eq!(store.history("a").len(), 2);
+
+    assert!(store.delete("b").unwrap());
+    assert!(!store.delete("b").unwrap());
+    assert!(store.pop_turn("b").unwrap().is_none());
+    assert!(!store.replace_reply("b", SessionMessage::new("assistant", "z", vec![])).unwrap());
+}
+
+#[test]
+fn test_session_expiry_and_cap() {
+    let store = MemoryStore::new(Some(60), Some(2));
+    let mut old = Session::new("old", Some("u"), None);
+    old.updated_at -= 61;
+    store.put(old);
+    assert!(store.get("old").is_none());
+
+    for (id, user_id) in [("1", "u"), ("2", "u"), ("3", "v")] {
+        let (user, assistant) = turn(id);
+        store.push_turn(id, Some(user_id), None, user, assistant).unwrap();
+    }
+    store.sessions.get_mut("1").unwrap().updated_at -= 10;
+    // A third session of "u" drops its least recently updated one
+    let (user, assistant) = turn("4");
+    store.push_turn("4", Some("u"), None, user, assistant).unwrap();
+    let mut ids: Vec<String> = store.list().into_iter().map(|s| s.id).collect();
+    ids.sort();
+    assert_eq!(ids, ["2", "3", "4"]);
+    // More turns in an existing session are always fine
+    let (user, assistant) = turn("5");
+    store.push_turn("2", Some("u"), None, user, assistant).unwrap();
+    assert_eq!(store.list().len(), 3);
+    // Another key naming the same user does not count against its sessions
+    let (user, assistant) = turn("6");
+    store.push_turn("6", Some("u"), owner("mallory"), user, assistant).unwrap();
+    assert_eq!(store.list().len(), 4);
+
+    // New sessions started at once still respect the cap
+    let store = MemoryStore::new(None, Some(2));
+    std::thread::scope(|scope| {
+        for i in 0..8 {
+            let store = &store;
+            scope.spawn(move || {
+                let (user, assistant) = turn("x");
+                store.push_turn(&i.to_string(), Some("u"), None, user, assistant).unwrap();
+            });
+        }
+    });
+    assert_eq!(store.list().len(), 2);
+}
+
+#[test]
+fn test_jsonl_store() {
+    let path = std::env::temp_dir().join(format!("sessions-{}-{}.jsonl", std::process::id(), now()));
+    let store = JsonlStore::open(&path, MemoryStore::new(None, Some(1))).unwrap();
+    for (id, text) in [("a", "one"), ("a", "two"), ("b", "three")] {
+        let (user, assistant) = turn(text);
+        store.push_turn(id, Some(id), owner("key"), user, assistant).unwrap();
+    }
+    // Each turn is logged on its own, not with the session so far
+    assert!(std::fs::read_to_string(&path).unwrap().lines().all(|line| line.starts_with("{\"turn\"")));
+    store.pop_turn("a").unwrap();
+    store.replace_reply("b", SessionMessage::new("assistant", "four", vec![])).unwrap();
+    let (user, assistant) = turn("five");
+    store.push_turn("c", Some("b"), owner("key"), user, assistant).unwrap(); // evicts "b"
+    store.delete("missing").unwrap();
+    let expected: Vec<Option<Session>> = ["a", "b", "c"].iter().map(|id| store.get(id)).collect();
+    assert!(expected[1].is_none());
+    drop(store);
+
+    // Simulate a crash in the middle of a write
+    let mut log = OpenOptions::new().append(true).open(&path).unwrap();
+    write!(log, "{{\"put\": {{\"id\"").unwrap();
+    let store = JsonlStore::open(&path, MemoryStore::new(None, Some(1))).unwrap();
+    assert_eq!(["a", "b", "c"].iter().map(|id| store.get(id)).collect::<Vec<_>>(), expected);
+    assert_eq!(store.get("a").unwrap().owner.as_deref(), Some("key"));
+    // Reopening compacted the log to one record per live session
+    assert_eq!(std::fs::read_to_string(&path).unwrap().lines().count(), 2);
+    std::fs::remove_file(&path).unwrap();
+}
+
+#[test]
+fn test_jsonl_store_expiry() {
+    let path = std::env::temp_dir().join(format!("sessions-expiry-{}-{}.jsonl", std::process::id(), now()));
+    let store = JsonlStore::open(&path, MemoryStore::new(Some(60), None)).unwrap();
+    let (user, assistant) = turn("old");
+    store.push_turn("a", None, owner("alice"), user, assistant).unwrap();
+    store.memory.sessions.get_mut("a").unwrap().updated_at -= 61;
+    // The expired session is dropped when its id is used again
+    let (user, assistant) = turn("new");
+    store.push_turn("a", None, owner("bob"), user, assistant).unwrap();
+    drop(store);
+
+    let store = JsonlStore::open(&path, MemoryStore::new(Some(60), None)).unwrap();
+    let session = store.get("a").unwrap();
+    assert_eq!(session.owner.as_deref(), Some("bob"));
+    assert_eq!(session.messages.iter().map(|m| m.content.as_str()).collect::<Vec<_>>(), ["new", "new"]);
+    drop(store);
+
+    // Expiry found by a read is logged as well
+    let store = JsonlStore::open(&path, MemoryStore::new(Some(60), None)).unwrap();
+    store.memory.sessions.get_mut("a").unwrap().updated_at -= 61;
+    assert!(store.get("a").is_none());
+    let (user, assistant) = turn("newer");
+    store.push_turn("a", None, owner("carol"), user, assistant).unwrap();
+    drop(store);
+    let store = JsonlStore::open(&path, MemoryStore::new(Some(60), None)).unwrap();
+    assert_eq!(store.get("a").unwrap().messages.iter().map(|m| m.content.as_str()).collect::<Vec<_>>(), ["newer", "newer"]);
+    std::fs::remove_file(&path).unwrap();
+}
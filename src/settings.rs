@@ -0,0 +1,376 @@
+use crate::kvcache::CacheDtype;
+use crate::session::{SessionConfig, StoreBackend};
+use clap::{Args, Parser, Subcommand, ValueEnum};
+use serde::{Deserialize, Serialize};
+use std::error::Error;
+use std::fs::File;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::Mutex;
+use tracing_chrome::{ChromeLayerBuilder, FlushGuard};
+use tracing_subscriber::filter::LevelFilter;
+use tracing_subscriber::fmt::{self, writer::BoxMakeWriter};
+use tracing_subscriber::prelude::*;
+
+// Command-line flags; each one overrides the matching setting of the file.
+#[derive(Parser, Debug, Default)]
+#[command(version, about = "Serve llama models over HTTP")]
+pub struct Cli {
+    #[arg(short, long, help = "TOML settings file")]
+    pub config: Option<PathBuf>,
+    #[arg(long, help = "Address to listen on, e.g. 0.0.0.0:8080")]
+    pub bind: Option<String>,
+    #[arg(long, help = "HTTP worker threads, one per core by default")]
+    pub workers: Option<usize>,
+    #[arg(long, help = "Directory of the /story model")]
+    pub story_model: Option<PathBuf>,
+    #[arg(long, help = "Directory of the /chat model")]
+    pub chat_model: Option<PathBuf>,
+    #[arg(long, help = "Directory of a small model drafting tokens for the /chat model")]
+    pub draft_model: Option<PathBuf>,
+    #[arg(long, help = "Threads for the compute kernels, one per core by default")]
+    pub threads: Option<usize>,
+    #[arg(long, value_enum, help = "Load the models as this type instead of their torch_dtype")]
+    pub dtype: Option<WeightDtype>,
+    #[arg(long, help = "Memory budget for the KV cache of one request, in MiB")]
+    pub kv_cache_mb: Option<usize>,
+    #[arg(long, help = "Generations to run at once")]
+    pub max_concurrent: Option<usize>,
+    #[arg(long, help = "Requests that may wait for a free generation slot")]
+    pub max_queue: Option<usize>,
+    #[arg(long, help = "Stop generating for a request after this many seconds")]
+    pub timeout_secs: Option<u64>,
+    #[arg(long, help = "One of off, error, warn, info, debug and trace")]
+    pub log_level: Option<String>,
+    #[arg(long, help = "Append logs to this file instead of writing them to stdout")]
+    pub log_file: Option<PathBuf>,
+    #[arg(long, help = "Require bearer tokens from this TOML file of API keys")]
+    pub key_file: Option<PathBuf>,
+    #[arg(long, value_enum, help = "Write logs as plain text or as JSON lines")]
+    pub log_format: Option<LogFormat>,
+    #[arg(long, help = "Record every span, down to single layers, to this Chrome trace file")]
+    pub trace_file: Option<PathBuf>,
+    #[command(subcommand)]
+    pub command: Option<Command>,
+}
+
+// Without a command, the server runs.
+#[derive(Subcommand, Debug)]
+pub enum Command {
+    // Print the perplexity of a model on a text file
+    Eval(EvalArgs),
+}
+
+#[derive(Args, Debug)]
+pub struct EvalArgs {
+    #[arg(help = "Text file to score")]
+    pub path: PathBuf,
+    #[arg(long, help = "Model directory, the /story model by default")]
+    pub model: Option<PathBuf>,
+    #[arg(long, help = "Tokens scored at once, the model's context length by default")]
+    pub window: Option<usize>,
+    #[arg(long, help = "Tokens each window moves on by, half the window by default")]
+    pub stride: Option<usize>,
+    #[arg(long, value_enum, default_value = "f32", help = "Element type of the KV cache")]
+    pub kv_cache_dtype: CacheDtype,
+}
+
+#[derive(Serialize, Deserialize, ValueEnum, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum WeightDtype {
+    F32,
+    BF16,
+}
+
+#[derive(Deserialize, ValueEnum, Debug, Clone, Copy, Default, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+    #[default]
+    Text,
+    Json,
+}
+
+// Everything the server can be told at startup. Relative paths in a settings
+// file are taken relative to the file; those given as flags to the working
+// directory.
+//
+//     [server]
+//     bind = "0.0.0.0:8080"
+//     workers = 4
+//
+//     [models]
+//     chat = "/srv/models/chat"
+//
+//     [compute]
+//     threads = 8
+//     dtype = "bf16"
+//     kv_cache_mb = 512
+//     max_concurrent = 4
+//
+//     [logging]
+//     level = "debug"
+//     file = "server.log"
+//     format = "json"
+//     trace_file = "trace.json"
+//
+//     [auth]
+//     key_file = "keys.toml"
+//     public_metrics = true
+//
+//     [sessions]
+//     ttl_secs = 86400
+//     store = { backend = "jsonl", path = "sessions.jsonl" }
+#[derive(Deserialize, Debug, Clone, Default, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct Settings {
+    pub server: ServerSettings,
+    pub models: ModelSettings,
+    pub compute: ComputeSettings,
+    pub generation: GenerationSettings,
+    pub logging: LoggingSettings,
+    pub sessions: SessionConfig,
+    pub auth: AuthSettings,
+}
+
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct ServerSettings {
+    pub bind: String,
+    pub workers: Option<usize>,
+}
+
+impl Default for ServerSettings {
+    fn default() -> Self {
+        ServerSettings { bind: "127.0.0.1:8080".into(), workers: None }
+    }
+}
+
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct ModelSettings {
+    pub story: PathBuf,
+    pub chat: PathBuf,
+    // Drafts tokens for the chat model in speculative decoding; it must share
+    // the chat model's vocabulary
+    pub draft: Option<PathBuf>,
+}
+
+impl Default for ModelSettings {
+    fn default() -> Self {
+        ModelSettings { story: PathBuf::from("models/story"), chat: PathBuf::from("models/chat"), draft: None }
+    }
+}
+
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct ComputeSettings {
+    pub threads: Option<usize>,
+    pub dtype: Option<WeightDtype>,
+    pub kv_cache_mb: Option<usize>,
+    // Generations running at once; more wait in a queue of `max_queue`, and
+    // past that requests get 429
+    pub max_concurrent: usize,
+    pub max_queue: usize,
+    // Queued requests that wait longer get 503
+    pub queue_timeout_secs: u64,
+}
+
+impl Default for ComputeSettings {
+    fn default() -> Self {
+        ComputeSettings { threads: None, dtype: None, kv_cache_mb: None, max_concurrent: 2, max_queue: 32, queue_timeout_secs: 60 }
+    }
+}
+
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct GenerationSettings {
+    // Most tokens in a chat reply
+    pub max_tokens: usize,
+    // Most tokens in a story
+    pub story_max_tokens: usize,
+    // Longest a request may generate for
+    pub timeout_secs: Option<u64>,
+}
+
+impl Default for GenerationSettings {
+    fn default() -> Self {
+        GenerationSettings { max_tokens: 100, story_max_tokens: 200, timeout_secs: None }
+    }
+}
+
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct LoggingSettings {
+    pub level: String,
+    // Logs go to stdout unless a file is given
+    pub file: Option<PathBuf>,
+    pub format: LogFormat,
+    // Chrome trace of all spans whatever the level, for chrome://tracing or Perfetto
+    pub trace_file: Option<PathBuf>,
+}
+
+impl Default for LoggingSettings {
+    fn default() -> Self {
+        LoggingSettings { level: "info".into(), file: None, format: LogFormat::Text, trace_file: None }
+    }
+}
+
+#[derive(Deserialize, Debug, Clone, Default, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct AuthSettings {
+    // TOML list of API keys; without one, anyone may use the server
+    pub key_file: Option<PathBuf>,
+    // Let `/metrics` be scraped without a key
+    pub public_metrics: bool,
+}
+
+impl Settings {
+    pub fn from_toml(text: &str, base_dir: &Path) -> Result<Self, Box<dyn Error>> {
+        let mut settings: Settings = toml::from_str(text)?;
+        settings.models.story = base_dir.join(&settings.models.story);
+        settings.models.chat = base_dir.join(&settings.models.chat);
+        for file in [&mut settings.models.draft, &mut settings.logging.file, &mut settings.logging.trace_file, &mut settings.auth.key_file].into_iter().flatten() {
+            *file = base_dir.join(&*file);
+        }
+        if let StoreBackend::Jsonl { path } = &mut settings.sessions.store {
+            *path = base_dir.join(&*path);
+        }
+        Ok(settings)
+    }
+
+    // The settings file named by `cli`, if any, overridden by its flags.
+    pub fn load(cli: &Cli) -> Result<Self, Box<dyn Error>> {
+        let mut settings = match &cli.config {
+            Some(path) => {
+                let text = std::fs::read_to_string(path).map_err(|e| format!("{}: {e}", path.display()))?;
+                let base_dir = path.parent().unwrap_or(Path::new(""));
+                Self::from_toml(&text, base_dir).map_err(|e| format!("{}: {e}", path.display()))?
+            }
+            None => Settings::default(),
+        };
+        if let Some(bind) = &cli.bind {
+            settings.server.bind = bind.clone();
+        }
+        if let Some(workers) = cli.workers {
+            settings.server.workers = Some(workers);
+        }
+        if let Some(path) = &cli.story_model {
+            settings.models.story = path.clone();
+        }
+        if let Some(path) = &cli.chat_model {
+            settings.models.chat = path.clone();
+        }
+        if let Some(path) = &cli.draft_model {
+            settings.models.draft = Some(path.clone());
+        }
+        if let Some(threads) = cli.threads {
+            settings.compute.threads = Some(threads);
+        }
+        if let Some(dtype) = cli.dtype {
+            settings.compute.dtype = Some(dtype);
+        }
+        if let Some(mb) = cli.kv_cache_mb {
+            settings.compute.kv_cache_mb = Some(mb);
+        }
+        if let Some(n) = cli.max_concurrent {
+            settings.compute.max_concurrent = n;
+        }
+        if let Some(n) = cli.max_queue {
+            settings.compute.max_queue = n;
+        }
+        if let Some(secs) = cli.timeout_secs {
+            settings.generation.timeout_secs = Some(secs);
+        }
+        if let Some(level) = &cli.log_level {
+            settings.logging.level = level.clone();
+        }
+        if let Some(file) = &cli.log_file {
+            settings.logging.file = Some(file.clone());
+        }
+        if let Some(file) = &cli.key_file {
+            settings.auth.key_file = Some(file.clone());
+        }
+        if let Some(format) = cli.log_format {
+            settings.logging.format = format;
+        }
+        if let Some(file) = &cli.trace_file {
+            settings.logging.trace_file = Some(file.clone());
+        }
+        LevelFilter::from_str(&settings.logging.level).map_err(|_| format!("unknown log level '{}'", settings.logging.level))?;
+        Ok(settings)
+    }
+
+    // Set up logging and the compute thread pool; call once at startup. The
+    // trace file is only complete once the returned guard is dropped.
+    pub fn init(&self) -> Result<Option<FlushGuard>, Box<dyn Error>> {
+        let level = LevelFilter::from_str(&self.logging.level)?;
+        let writer = match &self.logging.file {
+            Some(path) => BoxMakeWriter::new(Mutex::new(File::options().create(true).append(true).open(path)?)),
+            None => BoxMakeWriter::new(std::io::stdout),
+        };
+        let logger = fmt::layer().with_ansi(self.logging.file.is_none()).with_writer(writer);
+        let logger = match self.logging.format {
+            LogFormat::Text => logger.boxed(),
+            LogFormat::Json => logger.json().boxed(),
+        };
+        let (tracer, guard) = match &self.logging.trace_file {
+            Some(path) => {
+                let (layer, guard) = ChromeLayerBuilder::new().file(path).include_args(true).build();
+                (Some(layer), Some(guard))
+            }
+            None => (None, None),
+        };
+        tracing_subscriber::registry().with(logger.with_filter(level)).with(tracer).init();
+        if let Some(threads) = self.compute.threads {
+            rayon::ThreadPoolBuilder::new().num_threads(threads).build_global()?;
+        }
+        Ok(guard)
+    }
+}
+
+#[test]
+fn test_settings() {
+    let text = r#"
+        [server]
+        bind = "0.0.0.0:9000"
+
+        [models]
+        chat = "/srv/chat"
+        story = "story"
+        draft = "draft"
+
+        [compute]
+        dtype = "bf16"
+
+        [auth]
+        public_metrics = true
+
+        [sessions]
+        max_sessions_per_user = 3
+        store = { backend = "jsonl", path = "sessions.jsonl" }
+    "#;
+    let settings = Settings::from_toml(text, Path::new("/etc/lm")).unwrap();
+    assert_eq!(settings.server, ServerSettings { bind: "0.0.0.0:9000".into(), workers: None });
+    assert_eq!(settings.models.chat, PathBuf::from("/srv/chat"));
+    assert_eq!(settings.models.story, PathBuf::from("/etc/lm/story"));
+    assert_eq!(settings.models.draft, Some(PathBuf::from("/etc/lm/draft")));
+    assert_eq!(settings.compute.dtype, Some(WeightDtype::BF16));
+    assert_eq!(settings.generation, GenerationSettings::default());
+    assert!(settings.auth.public_metrics);
+    assert_eq!(settings.sessions.max_sessions_per_user, Some(3));
+    assert_eq!(
+        settings.sessions.store,
+        StoreBackend::Jsonl { path: PathBuf::from("/etc/lm/sessions.jsonl") }
+    );
+    assert!(Settings::from_toml("[server]\nport = 1\n", Path::new("")).is_err());
+
+    let cli = Cli::parse_from(["lm", "--bind", "[::]:80", "--threads", "2", "--dtype", "f32", "--log-level", "debug", "--log-format", "json"]);
+    let settings = Settings::load(&cli).unwrap();
+    assert_eq!(settings.server.bind, "[::]:80");
+    assert_eq!(settings.compute.threads, Some(2));
+    assert_eq!(settings.compute.dtype, Some(WeightDtype::F32));
+    assert_eq!(settings.logging.level, "debug");
+    assert_eq!(settings.logging.format, LogFormat::Json);
+    assert_eq!(settings.models, ModelSettings::default());
+    assert!(Settings::load(&Cli::parse_from(["lm", "--log-level", "loud"])).is_err());
+}
@@ -0,0 +1,234 @@
+use rand::Rng;
+use serde::Serialize;
+use crate::kvcache::KVElem;
+use crate::metrics::{GaugeGuard, TokenTimer, METRICS};
+use crate::model::{GenerateError, Llama};
+use crate::params::Load;
+use crate::operators::ToF32;
+use crate::sampler::Sampler;
+use crate::stop::{FinishReason, StopCriteria};
+use crate::tensor::Tensor;
+
+pub struct Speculation {
+    pub tokens: Vec<u32>,
+    pub finish_reason: FinishReason,
+    pub drafted: usize,  // tokens proposed by the draft model
+    pub accepted: usize, // of which the target model kept
+}
+
+impl Speculation {
+    pub fn acceptance_rate(&self) -> f32 {
+        if self.drafted == 0 { 0. } else { self.accepted as f32 / self.drafted as f32 }
+    }
+
+    pub fn stats(&self) -> DraftStats {
+        DraftStats { drafted: self.drafted, accepted: self.accepted, acceptance_rate: self.acceptance_rate() }
+    }
+}
+
+// How well the draft model did, as reported with a reply.
+#[derive(Serialize, Debug, Clone, Copy, PartialEq)]
+pub struct DraftStats {
+    pub drafted: usize,
+    pub accepted: usize,
+    pub acceptance_rate: f32,
+}
+
+// Speculative decoding: `draft` proposes `k` tokens which `target` scores in a
+// single forward pass. Each proposal is kept with probability min(1, p / q) and
+// the first rejected one is resampled from max(0, p - q), where p and q are the
+// distributions `sampler` draws from, so the tokens follow the target's one
+// exactly. Stops like `Llama::generate`, except that running out of context
+// ends the reply instead of shifting or failing.
+pub fn generate<T, D, C, E>(
+    target: &Llama<T>,
+    draft: &Llama<D>,
+    token_ids: &[u32],
+    max_len: usize,
+    k: usize,
+    sampler: &mut Sampler,
+    stop: &StopCriteria,
+) -> Result<Speculation, GenerateError>
+where
+    T: Default + Copy + ToF32 + Load,
+    D: Default + Copy + ToF32 + Load,
+    C: KVElem, // target cache
+    E: KVElem, // draft cache
+{
+    assert!(k >= 1 && !token_ids.is_empty());
+    assert_eq!(target.vocab(), draft.vocab(), "draft and target models must share the vocabulary");
+    let max_seq_len = target.max_seq_len().min(draft.max_seq_len());
+    if token_ids.len() > max_seq_len {
+        return Err(GenerateError::ContextOverflow { len: token_ids.len(), max_seq_len });
+    }
+
+    // Both caches hold everything but the last token, which is fed next
+    let mut timer = TokenTimer::start();
+    let (mut target_cache, mut draft_cache) = (target.new_cache::<C>(), draft.new_cache::<E>());
+    let _cache_bytes = GaugeGuard::add(&METRICS.kv_cache_bytes, (target_cache.bytes() + draft_cache.bytes()) as i64);
+    let (prefix, mut last) = (&token_ids[..token_ids.len() - 1], token_ids[token_ids.len() - 1]);
+    if !prefix.is_empty() {
+        target.forward(&Tensor::new(prefix.to_vec(), &[prefix.len()]), &mut target_cache);
+        draft.forward(&Tensor::new(prefix.to_vec(), &[prefix.len()]), &mut draft_cache);
+    }
+
+    let mut history = token_ids.to_vec(); // what the sampler penalizes repetitions of
+    let mut result = Speculation { tokens: Vec::new(), finish_reason: FinishReason::Length, drafted: 0, accepted: 0 };
+    while result.tokens.len() < max_len {
+        if let Some(reason) = stop.interrupted() {
+            result.finish_reason = reason;
+            break;
+        }
+        let base = target_cache.len();
+        let k = k.min(max_len - result.tokens.len()).min(max_seq_len - base - 1);
+        if k == 0 {
+            result.finish_reason = FinishReason::ContextFull;
+            break;
+        }
+
+        // Draft k tokens, each sampled given the ones before
+        let mut q = Vec::with_capacity(k);
+        let mut input = last;
+        for _ in 0..k {
+            let dist = sampler.distribution(draft.forward(&Tensor::new(vec![input], &[1]), &mut draft_cache).data(), &history);
+            input = draw(&dist, sampler.rng());
+            history.push(input);
+            q.push(dist);
+        }
+        let drafted = history.split_off(history.len() - k);
+
+        let mut verify = vec![last];
+        verify.extend(&drafted);
+        let logits = target.forward_all(&Tensor::new(verify, &[k + 1]), &mut target_cache);
+        let vocab = target.vocab();
+        let mut p = Vec::with_capacity(k + 1);
+        for i in 0..=k {
+            p.push(sampler.distribution(&logits.data()[i * vocab..][..vocab], &history));
+            history.extend(drafted.get(i));
+        }
+        history.truncate(history.len() - k);
+
+        let (n_accepted, next) = accept(&p, &q, &drafted, sampler.rng());
+        result.drafted += k;
+        result.accepted += n_accepted;
+        timer.tokens((n_accepted + 1).min(max_len - result.tokens.len()));
+        if n_accepted == k {
+            // The draft has not seen its own last proposal yet
+            draft.forward(&Tensor::new(vec![drafted[k - 1]], &[1]), &mut draft_cache);
+        } else {
+            target_cache.truncate(base + n_accepted + 1);
+            draft_cache.truncate(base + n_accepted + 1);
+        }
+
+        for tok in drafted.into_iter().take(n_accepted).chain([next]) {
+            if result.tokens.len() == max_len {
+                break;
+            }
+            if target.is_eos(tok) || stop.is_stop_token(tok) {
+                result.finish_reason = FinishReason::StopToken(tok);
+                return Ok(finish(result, &timer));
+            }
+            history.push(tok);
+            result.tokens.push(tok);
+            stop.emit(tok);
+            if let Some(i) = stop.find_string(&result.tokens) {
+                result.finish_reason = FinishReason::StopString(i);
+                return Ok(finish(result, &timer));
+            }
+        }
+        last = next;
+    }
+    Ok(finish(result, &timer))
+}
+
+fn finish(result: Speculation, timer: &TokenTimer) -> Speculation {
+    METRICS.generated_tokens.inc_by(result.tokens.len() as u64);
+    timer.finish(result.tokens.len());
+    result
+}
+
+// Verify the drafted tokens against the target distributions `p` (one more
+// than drafted) and the draft ones `q`. Returns how many were accepted and the
+// token to emit after them.
+fn accept(p: &[Vec<f32>], q: &[Vec<f32>], drafted: &[u32], rng: &mut impl Rng) -> (usize, u32) {
+    for (i, &tok) in drafted.iter().enumerate() {
+        let (p_tok, q_tok) = (p[i][tok as usize], q[i][tok as usize]);
+        if q_tok > 0. && rng.gen::<f32>() * q_tok < p_tok {
+            continue;
+        }
+        let residual = p[i].iter().zip(&q[i]).map(|(a, b)| (a - b).max(0.)).collect::<Vec<_>>();
+        let next = if residual.iter().sum::<f32>() > 0. { draw(&residual, rng) } else { draw(&p[i], rng) };
+        return (i, next);
+    }
+    (drafted.len(), draw(&p[drafted.len()], rng))
+}
+
+// Draw an index from unnormalized weights.
+fn draw(weights: &[f32], rng: &mut impl Rng) -> u32 {
+    let plimit = rng.gen::<f32>() * weights.iter().sum::<f32>();
+    let mut cum = 0.;
+    weights.iter()
+        .position(|w| { cum += w; cum > plimit })
+        .unwrap_or_else(|| weights.iter().rposition(|w| *w > 0.).unwrap()) as u32
+}
+
+#[test]
+fn test_accept_keeps_target_distribution() {
+    use rand::SeedableRng;
+    let rng = &mut rand::rngs::StdRng::seed_from_u64(0);
+    let p = vec![vec![0.5, 0.3, 0.2], vec![1. / 3.; 3]];
+    let q = vec![vec![0.2, 0.2, 0.6]];
+    let n = 40000;
+    let mut counts = [0; 3];
+    let mut accepted = 0;
+    for _ in 0..n {
+        let drafted = draw(&q[0], rng);
+        let (n_accepted, next) = accept(&p, &q, &[drafted], rng);
+        accepted += n_accepted;
+        counts[if n_accepted == 1 { drafted } else { next } as usize] += 1;
+    }
+    // The first emitted token follows p, whatever the draft proposes
+    for (count, p) in counts.iter().zip(&p[0]) {
+        assert!((*count as f32 / n as f32 - p).abs() < 0.01);
+    }
+    // and the draft gets accepted sum(min(p, q)) = 0.6 of the time
+    assert!((accepted as f32 / n as f32 - 0.6).abs() < 0.01);
+}
+
+#[test]
+fn test_speculative_generate() {
+    use std::path::PathBuf;
+    use crate::model::ContextOverflow;
+    use crate::stop::CancelToken;
+    let project_dir = env!("CARGO_MANIFEST_DIR");
+    let model_dir = PathBuf::from(project_dir).join("models").join("story");
+    let llama = Llama::<f32>::from_safetensors(model_dir);
+    let prompt = [1u32, 400, 35];
+    let none = &StopCriteria::none();
+
+    // Greedy speculation reproduces greedy decoding of the target, even with
+    // a draft that reads from a lossy int8 cache
+    let greedy = llama.generate::<f32>(&prompt, 20, &mut Sampler::new(0.8, 1, 1.), ContextOverflow::Error, none, None).unwrap().tokens;
+    let speculation = generate::<f32, f32, f32, i8>(&llama, &llama, &prompt, 20, 4, &mut Sampler::new(0.8, 1, 1.), none).unwrap();
+    assert_eq!(speculation.tokens, greedy);
+    assert_eq!(speculation.finish_reason, FinishReason::Length);
+
+    // A draft identical to the target is always right
+    let sampler = &mut Sampler::new(0.9, 40, 1.).with_seed(0);
+    let speculation = generate::<f32, f32, f32, f32>(&llama, &llama, &prompt, 20, 3, sampler, none).unwrap();
+    assert_eq!(speculation.acceptance_rate(), 1.);
+    assert!(speculation.tokens.len() <= 20);
+
+    // Stop tokens end the reply wherever they fall in a drafted run
+    let stop = StopCriteria::new(vec![greedy[5]], Vec::new(), |_| String::new());
+    let speculation = generate::<f32, f32, f32, f32>(&llama, &llama, &prompt, 20, 4, &mut Sampler::new(0.8, 1, 1.), &stop).unwrap();
+    assert_eq!(speculation.tokens, greedy[..5]);
+    assert_eq!(speculation.finish_reason, FinishReason::StopToken(greedy[5]));
+
+    let cancel = CancelToken::new();
+    cancel.cancel();
+    let stop = StopCriteria::none().with_cancel(cancel);
+    let speculation = generate::<f32, f32, f32, f32>(&llama, &llama, &prompt, 20, 4, &mut Sampler::new(0.8, 1, 1.), &stop).unwrap();
+    assert!(speculation.tokens.is_empty());
+    assert_eq!(speculation.finish_reason, FinishReason::Cancelled);
+}
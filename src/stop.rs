@@ -0,0 +1,182 @@
+use std::fmt;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use serde::Serialize;
+
+// Why a generation ended.
+#[derive(Serialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum FinishReason {
+    Length,
+    // The context window filled up and could not be shifted
+    ContextFull,
+    // A model end-of-sequence token or a requested stop token was sampled,
+    // it is not part of the output
+    StopToken(u32),
+    // The decoded output contains the stop string at this index, the output
+    // ends right before it
+    StopString(usize),
+    // The caller gave up, e.g. the client disconnected
+    Cancelled,
+    // The request ran out of time
+    Timeout,
+}
+
+impl fmt::Display for FinishReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FinishReason::Length => write!(f, "length"),
+            FinishReason::ContextFull => write!(f, "context_full"),
+            FinishReason::StopToken(id) => write!(f, "stop_token:{id}"),
+            FinishReason::StopString(i) => write!(f, "stop_string:{i}"),
+            FinishReason::Cancelled => write!(f, "cancelled"),
+            FinishReason::Timeout => write!(f, "timeout"),
+        }
+    }
+}
+
+// Lets another thread stop a running generation between two decode steps.
+// Clones share the flag; the deadline belongs to each clone.
+#[derive(Clone, Default)]
+pub struct CancelToken {
+    cancelled: Arc<AtomicBool>,
+    deadline: Option<Instant>,
+}
+
+impl CancelToken {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.deadline = Some(Instant::now() + timeout);
+        self
+    }
+
+    pub fn cancel(&self) {
+        self.cancelled.store(true, Ordering::Relaxed);
+    }
+
+    // Why the generation has to end now, if it has to.
+    pub fn check(&self) -> Option<FinishReason> {
+        if self.cancelled.load(Ordering::Relaxed) {
+            Some(FinishReason::Cancelled)
+        } else if self.deadline.is_some_and(|deadline| Instant::now() >= deadline) {
+            Some(FinishReason::Timeout)
+        } else {
+            None
+        }
+    }
+}
+
+// Cancels the token when dropped, e.g. along with the future of a request
+// whose client went away.
+pub struct CancelOnDrop(pub CancelToken);
+
+impl Drop for CancelOnDrop {
+    fn drop(&mut self) {
+        self.0.cancel();
+    }
+}
+
+type Decode<'a> = Box<dyn Fn(&[u32]) -> String + 'a>;
+type OnToken<'a> = Box<dyn Fn(u32) + 'a>;
+
+// Extra stop conditions for `Llama::generate`, on top of the model's own
+// end-of-sequence tokens. Stop strings are matched on the text decoded by
+// `decode`, so they are found even when split across several tokens.
+// Generation also ends once `cancel` fires, and every output token is
+// passed to `on_token` as soon as it is accepted, for streaming.
+pub struct StopCriteria<'a> {
+    token_ids: Vec<u32>,
+    strings: Vec<String>,
+    decode: Decode<'a>,
+    cancel: CancelToken,
+    on_token: Option<OnToken<'a>>,
+}
+
+impl<'a> StopCriteria<'a> {
+    pub fn new(token_ids: Vec<u32>, strings: Vec<String>, decode: impl Fn(&[u32]) -> String + 'a) -> Self {
+        StopCriteria { token_ids, strings, decode: Box::new(decode), cancel: CancelToken::new(), on_token: None }
+    }
+
+    pub fn with_cancel(mut self, cancel: CancelToken) -> Self {
+        self.cancel = cancel;
+        self
+    }
+
+    pub fn with_on_token(mut self, on_token: impl Fn(u32) + 'a) -> Self {
+        self.on_token = Some(Box::new(on_token));
+        self
+    }
+
+    pub fn interrupted(&self) -> Option<FinishReason> {
+        self.cancel.check()
+    }
+
+    pub fn emit(&self, token_id: u32) {
+        if let Some(on_token) = &self.on_token {
+            on_token(token_id);
+        }
+    }
+
+
+    pub fn none() -> Self {
+        Self::new(Vec::new(), Vec::new(), |_| String::new())
+    }
+
+    pub fn is_stop_token(&self, token_id: u32) -> bool {
+        self.token_ids.contains(&token_id)
+    }
+
+    // Index of the first stop string found in the output decoded so far.
+    pub fn find_string(&self, generated: &[u32]) -> Option<usize> {
+        if self.strings.is_empty() {
+            return None;
+        }
+        let text = (self.decode)(generated);
+        self.strings.iter()
+            .enumerate()
+            .filter_map(|(i, s)| text.find(s.as_str()).map(|pos| (pos, i)))
+            .min()
+            .map(|(_, i)| i)
+    }
+
+    // Cut the decoded output right before the stop string that ended it.
+    pub fn trim(&self, text: &mut String, reason: FinishReason) {
+        if let FinishReason::StopString(i) = reason {
+            if let Some(pos) = text.find(self.strings[i].as_str()) {
+                text.truncate(pos);
+            }
+        }
+    }
+}
+
+#[test]
+fn test_stop_string_across_tokens() {
+    let pieces = ["Hel", "lo", " wor", "ld", "!\n", "\nUser:"];
+    let decode = |ids: &[u32]| ids.iter().map(|&i| pieces[i as usize]).collect::<String>();
+    let stop = StopCriteria::new(vec![9], vec!["User:".to_string(), "\n\n".to_string()], decode);
+    assert_eq!(stop.find_string(&[0, 1, 2, 3]), None);
+    assert_eq!(stop.find_string(&[0, 1, 2, 3, 4]), None);
+    assert_eq!(stop.find_string(&[0, 1, 2, 3, 4, 5]), Some(1));
+    assert!(stop.is_stop_token(9));
+
+    let mut text = decode(&[0, 1, 2, 3, 4, 5]);
+    stop.trim(&mut text, FinishReason::StopString(1));
+    assert_eq!(text, "Hello world!");
+}
+
+#[test]
+fn test_cancel_token() {
+    let token = CancelToken::new();
+    let stop = StopCriteria::none().with_cancel(token.clone());
+    assert_eq!(stop.interrupted(), None);
+    drop(CancelOnDrop(token.clone()));
+    assert_eq!(stop.interrupted(), Some(FinishReason::Cancelled));
+
+    let expired = CancelToken::new().with_timeout(Duration::ZERO);
+    assert_eq!(expired.check(), Some(FinishReason::Timeout));
+    assert_eq!(CancelToken::new().with_timeout(Duration::from_secs(60)).check(), None);
+}
@@ -0,0 +1,146 @@
+use crate::speculative::DraftStats;
+use crate::stop::FinishReason;
+use actix_web::web::Bytes;
+use serde::Serialize;
+use serde_json::json;
+use std::cell::{Cell, RefCell};
+
+type Decode<'a> = Box<dyn Fn(&[u32]) -> String + 'a>;
+type SendDelta<'a> = Box<dyn Fn(&str) + 'a>;
+
+// Turns generated tokens into text deltas as they arrive. Text that may still
+// turn into a stop string, or that ends in an incomplete character, is held
+// back until the next token settles it.
+pub struct TextStream<'a> {
+    ids: RefCell<Vec<u32>>,
+    sent: Cell<usize>,
+    stop_strings: Vec<String>,
+    decode: Decode<'a>,
+    send: SendDelta<'a>,
+}
+
+impl<'a> TextStream<'a> {
+    pub fn new(stop_strings: &[String], decode: impl Fn(&[u32]) -> String + 'a, send: impl Fn(&str) + 'a) -> Self {
+        TextStream {
+            ids: RefCell::new(Vec::new()),
+            sent: Cell::new(0),
+            stop_strings: stop_strings.to_vec(),
+            decode: Box::new(decode),
+            send: Box::new(send),
+        }
+    }
+
+    pub fn push(&self, token_id: u32) {
+        self.ids.borrow_mut().push(token_id);
+        let text = (self.decode)(&self.ids.borrow());
+        let end = self.settled_len(&text);
+        self.send_upto(&text, end);
+    }
+
+    // Send the rest of the final, trimmed output.
+    pub fn finish(&self, text: &str) {
+        self.send_upto(text, text.len());
+    }
+
+    fn send_upto(&self, text: &str, end: usize) {
+        let sent = self.sent.get();
+        if end > sent && text.is_char_boundary(sent) {
+            (self.send)(&text[sent..end]);
+            self.sent.set(end);
+        }
+    }
+
+    fn settled_len(&self, text: &str) -> usize {
+        let mut end = text.trim_end_matches('\u{FFFD}').len();
+        for stop in &self.stop_strings {
+            if let Some(pos) = text[..end].find(stop.as_str()) {
+                end = pos;
+            }
+            // The longest tail that starts a stop string
+            let held = (1..stop.len()).rev()
+                .find(|&k| stop.is_char_boundary(k) && text[..end].ends_with(&stop[..k]))
+                .unwrap_or(0);
+            end -= held;
+        }
+        end
+    }
+}
+
+// Server-sent events of a streamed reply.
+pub fn text_event(text: &str) -> Bytes {
+    Bytes::from(format!("data: {}\n\n", json!({ "text": text })))
+}
+
+// The last event of a reply; the logprobs of all its tokens come with it
+// rather than with each piece of text, which may hold back or merge tokens.
+pub fn done_event(finish_reason: FinishReason, seed: u64, draft: Option<DraftStats>, logprobs: Option<impl Serialize>) -> Bytes {
+    let mut data = json!({ "finish_reason": finish_reason.to_string(), "seed": seed });
+    if let Some(draft) = draft {
+        data["draft"] = json!(draft);
+    }
+    if let Some(logprobs) = logprobs {
+        data["logprobs"] = json!(logprobs);
+    }
+    Bytes::from(format!("event: done\ndata: {data}\n\n"))
+}
+
+pub fn error_event(message: &str) -> Bytes {
+    Bytes::from(format!("event: error\ndata: {}\n\n", json!({ "message": message })))
+}
+
+// The same events as WebSocket text messages, told apart by their type.
+pub fn ws_text(text: &str) -> String {
+    json!({ "type": "text", "text": text }).to_string()
+}
+
+pub fn ws_done(finish_reason: FinishReason, seed: u64, draft: Option<DraftStats>, logprobs: Option<impl Serialize>) -> String {
+    let mut data = json!({ "type": "done", "finish_reason": finish_reason.to_string(), "seed": seed });
+    if let Some(draft) = draft {
+        data["draft"] = json!(draft);
+    }
+    if let Some(logprobs) = logprobs {
+        data["logprobs"] = json!(logprobs);
+    }
+    data.to_string()
+}
+
+pub fn ws_error(message: &str) -> String {
+    json!({ "type": "error", "message": message }).to_string()
+}
+
+#[test]
+fn test_text_stream() {
+    let pieces = ["Hel", "lo", " wor", "ld", "!\n", "\nUser:", "\u{FFFD}", "é"];
+    let decode = |ids: &[u32]| {
+        let text: String = ids.iter().map(|&i| pieces[i as usize]).collect();
+        text.replace("\u{FFFD}é", "é")
+    };
+    let sent = RefCell::new(Vec::<String>::new());
+    let stream = TextStream::new(&["\n\nUser:".to_string()], decode, |delta| sent.borrow_mut().push(delta.to_string()));
+    for id in [0, 1, 2, 3, 4] {
+        stream.push(id);
+    }
+    // The trailing newline may start the stop string
+    assert_eq!(sent.borrow().concat(), "Hello world!");
+    stream.push(5);
+    assert_eq!(sent.borrow().concat(), "Hello world!");
+    stream.finish("Hello world!");
+    assert_eq!(*sent.borrow(), ["Hel", "lo", " wor", "ld", "!"]);
+
+    let sent = RefCell::new(String::new());
+    let stream = TextStream::new(&[], decode, |delta| sent.borrow_mut().push_str(delta));
+    stream.push(0);
+    stream.push(6);
+    assert_eq!(*sent.borrow(), "Hel");
+    stream.push(7);
+    assert_eq!(*sent.borrow(), "Helé");
+}
+
+#[test]
+fn test_done_event() {
+    let event = done_event(FinishReason::Length, 7, None, Some([json!({ "id": 1, "logprob": -0.5 })]));
+    let data: serde_json::Value = serde_json::from_slice(&event["event: done\ndata: ".len()..]).unwrap();
+    assert_eq!(data, json!({ "finish_reason": "length", "seed": 7, "logprobs": [{ "id": 1, "logprob": -0.5 }] }));
+    let data: serde_json::Value = serde_json::from_str(&ws_done(FinishReason::Length, 7, None, None::<()>)).unwrap();
+    assert!(data.get("logprobs").is_none());
+}
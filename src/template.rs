@@ -0,0 +1,235 @@
+use minijinja::value::Kwargs;
+use minijinja::{context, Environment, ErrorKind};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::fmt;
+use std::path::Path;
+
+// Used when tokenizer_config.json has no `chat_template`.
+const CHATML_TEMPLATE: &str = "{% for message in messages %}\
+{{ '<|im_start|>' + message['role'] + '\n' + message['content'] + '<|im_end|>' + '\n' }}\
+{% endfor %}\
+{% if add_generation_prompt %}{{ '<|im_start|>assistant\n' }}{% endif %}";
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Message {
+    pub role: String,
+    pub content: String,
+}
+
+impl Message {
+    pub fn new(role: &str, content: impl Into<String>) -> Self {
+        Message { role: role.to_string(), content: content.into() }
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub struct TemplateError {
+    pub message: String,
+}
+
+impl fmt::Display for TemplateError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "chat template error: {}", self.message)
+    }
+}
+
+impl std::error::Error for TemplateError {}
+
+// What templates raise with `raise_exception` is reported as is, anything else
+// with where in the template it happened.
+impl From<minijinja::Error> for TemplateError {
+    fn from(e: minijinja::Error) -> Self {
+        let message = match (e.kind(), e.detail()) {
+            (ErrorKind::InvalidOperation, Some(detail)) => detail.to_string(),
+            _ => e.to_string(),
+        };
+        TemplateError { message }
+    }
+}
+
+// A Hugging Face chat template, rendered by minijinja the way
+// `apply_chat_template` renders it with Jinja2: `trim_blocks` and
+// `lstrip_blocks` on, Python's string and dict methods, a `tojson` that
+// formats like `json.dumps`, and `raise_exception`.
+pub struct ChatTemplate {
+    env: Environment<'static>,
+    bos_token: String,
+    eos_token: String,
+}
+
+#[derive(Deserialize)]
+struct TokenizerConfigJson {
+    #[serde(default)]
+    chat_template: Option<TemplateSource>,
+    #[serde(default)]
+    bos_token: Option<SpecialToken>,
+    #[serde(default)]
+    eos_token: Option<SpecialToken>,
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum TemplateSource {
+    One(String),
+    Named(Vec<NamedTemplate>),
+}
+
+#[derive(Deserialize)]
+struct NamedTemplate {
+    name: String,
+    template: String,
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum SpecialToken {
+    Text(String),
+    Added { content: String },
+}
+
+impl SpecialToken {
+    fn content(self) -> String {
+        match self {
+            SpecialToken::Text(s) | SpecialToken::Added { content: s } => s,
+        }
+    }
+}
+
+impl ChatTemplate {
+    pub fn new(source: &str, bos_token: &str, eos_token: &str) -> Result<Self, TemplateError> {
+        let mut env = Environment::new();
+        env.set_trim_blocks(true);
+        env.set_lstrip_blocks(true);
+        env.set_unknown_method_callback(minijinja_contrib::pycompat::unknown_method_callback);
+        env.add_function("raise_exception", |message: String| -> Result<(), minijinja::Error> {
+            Err(minijinja::Error::new(ErrorKind::InvalidOperation, message))
+        });
+        env.add_filter("tojson", tojson);
+        env.add_template_owned("chat", source.to_string())?;
+        Ok(ChatTemplate { env, bos_token: bos_token.to_string(), eos_token: eos_token.to_string() })
+    }
+
+    // The template of a model directory, falling back to ChatML.
+    pub fn from_model_dir(model_dir: impl AsRef<Path>) -> Result<Self, TemplateError> {
+        let config = match std::fs::read(model_dir.as_ref().join("tokenizer_config.json")) {
+            Ok(bytes) => serde_json::from_slice(&bytes).map_err(|e| TemplateError { message: e.to_string() })?,
+            Err(_) => TokenizerConfigJson { chat_template: None, bos_token: None, eos_token: None },
+        };
+        let source = match config.chat_template {
+            Some(TemplateSource::One(source)) => source,
+            Some(TemplateSource::Named(templates)) => templates
+                .into_iter()
+                .find(|t| t.name == "default")
+                .map(|t| t.template)
+                .unwrap_or_else(|| CHATML_TEMPLATE.to_string()),
+            None => CHATML_TEMPLATE.to_string(),
+        };
+        let bos_token = config.bos_token.map(SpecialToken::content).unwrap_or_default();
+        let eos_token = config.eos_token.map(SpecialToken::content).unwrap_or_default();
+        Self::new(&source, &bos_token, &eos_token)
+    }
+
+    pub fn eos_token(&self) -> &str {
+        &self.eos_token
+    }
+
+    pub fn render(&self, messages: &[Message], add_generation_prompt: bool) -> Result<String, TemplateError> {
+        let template = self.env.get_template("chat")?;
+        Ok(template.render(context! {
+            messages,
+            add_generation_prompt,
+            bos_token => self.bos_token,
+            eos_token => self.eos_token,
+        })?)
+    }
+}
+
+// `tojson`, with the indent given either way Jinja2 takes it.
+fn tojson(value: minijinja::Value, indent: Option<usize>, kwargs: Kwargs) -> Result<String, minijinja::Error> {
+    let indent = match indent {
+        Some(indent) => Some(indent),
+        None => kwargs.get("indent")?,
+    };
+    let json = serde_json::to_value(&value).map_err(|e| minijinja::Error::new(ErrorKind::BadSerialization, e.to_string()))?;
+    Ok(to_json(&json, indent, 0))
+}
+
+// Like Python's `json.dumps`, which is what `tojson` uses in Hugging Face
+// templates: ", " and ": " separators, or newlines with `indent`.
+fn to_json(v: &Value, indent: Option<usize>, level: usize) -> String {
+    let (open, sep, close) = match indent {
+        Some(n) => (format!("\n{}", " ".repeat(n * (level + 1))), format!(",\n{}", " ".repeat(n * (level + 1))), format!("\n{}", " ".repeat(n * level))),
+        None => (String::new(), ", ".to_string(), String::new()),
+    };
+    match v {
+        Value::Array(items) if !items.is_empty() => {
+            let items: Vec<String> = items.iter().map(|v| to_json(v, indent, level + 1)).collect();
+            format!("[{open}{}{close}]", items.join(&sep))
+        }
+        Value::Object(map) if !map.is_empty() => {
+            let items: Vec<String> = map
+                .iter()
+                .map(|(k, v)| format!("{}: {}", Value::from(k.as_str()), to_json(v, indent, level + 1)))
+                .collect();
+            format!("{{{open}{}{close}}}", items.join(&sep))
+        }
+        v => v.to_string(),
+    }
+}
+
+#[test]
+fn test_chatml_template() {
+    let template = ChatTemplate::from_model_dir("no such dir").unwrap();
+    let messages = [Message::new("system", "Be brief."), Message::new("user", "Hi")];
+    assert_eq!(
+        template.render(&messages, true).unwrap(),
+        "<|im_start|>system\nBe brief.<|im_end|>\n<|im_start|>user\nHi<|im_end|>\n<|im_start|>assistant\n"
+    );
+}
+
+#[test]
+fn test_llama2_template() {
+    // As shipped with meta-llama/Llama-2-7b-chat-hf
+    let source = "{% if messages[0]['role'] == 'system' %}{% set loop_messages = messages[1:] %}{% set system_message = messages[0]['content'] %}{% else %}{% set loop_messages = messages %}{% set system_message = false %}{% endif %}{% for message in loop_messages %}{% if (message['role'] == 'user') != (loop.index0 % 2 == 0) %}{{ raise_exception('Conversation roles must alternate user/assistant/user/assistant/...') }}{% endif %}{% if loop.index0 == 0 and system_message != false %}{% set content = '<<SYS>>\\n' + system_message + '\\n<</SYS>>\\n\\n' + message['content'] %}{% else %}{% set content = message['content'] %}{% endif %}{% if message['role'] == 'user' %}{{ bos_token + '[INST] ' + content.strip() + ' [/INST]' }}{% elif message['role'] == 'assistant' %}{{ ' '  + content.strip() + ' ' + eos_token }}{% endif %}{% endfor %}";
+    let template = ChatTemplate::new(source, "<s>", "</s>").unwrap();
+    let messages = [
+        Message::new("system", "Sys"),
+        Message::new("user", "Hi "),
+        Message::new("assistant", "Hello"),
+        Message::new("user", "Bye"),
+    ];
+    assert_eq!(
+        template.render(&messages, true).unwrap(),
+        "<s>[INST] <<SYS>>\nSys\n<</SYS>>\n\nHi [/INST] Hello </s><s>[INST] Bye [/INST]"
+    );
+    let error = template.render(&[Message::new("assistant", "Hello")], true).unwrap_err();
+    assert_eq!(error.message, "Conversation roles must alternate user/assistant/user/assistant/...");
+}
+
+#[test]
+fn test_whitespace_control() {
+    // Llama-3 style: `-` trims, and block tags take their own line's newline
+    let source = "{{- bos_token }}\n{%- for message in messages %}\n    {%- set content = '<|start_header_id|>' + message['role'] + '<|end_header_id|>\\n\\n' + message['content'] | trim + '<|eot_id|>' %}\n    {{- content }}\n{%- endfor %}\n{%- if add_generation_prompt %}\n    {{- '<|start_header_id|>assistant<|end_header_id|>\\n\\n' }}\n{%- endif %}\n";
+    let template = ChatTemplate::new(source, "<|begin_of_text|>", "<|eot_id|>").unwrap();
+    assert_eq!(
+        template.render(&[Message::new("user", " Hi ")], true).unwrap(),
+        "<|begin_of_text|><|start_header_id|>user<|end_header_id|>\n\nHi<|eot_id|><|start_header_id|>assistant<|end_header_id|>\n\n"
+    );
+    let template = ChatTemplate::new("{% for m in messages %}\n  {{ m.role }}\n{% endfor %}", "", "").unwrap();
+    assert_eq!(template.render(&[Message::new("user", ""), Message::new("assistant", "")], false).unwrap(), "  user\n  assistant\n");
+}
+
+#[test]
+fn test_template_expressions() {
+    let render = |source: &str| ChatTemplate::new(source, "", "").and_then(|t| t.render(&[Message::new("user", "a</think>b")], false));
+    assert_eq!(render("{{ messages | length }} {{ messages[-1].content.split('</think>')[-1] }}").unwrap(), "1 b");
+    assert_eq!(render("{% set ns = namespace(n=0) %}{% for i in range(3) %}{% set ns.n = ns.n + i %}{% endfor %}{{ ns.n }}").unwrap(), "3");
+    assert_eq!(render("{% for k, v in {'a': 1, 'b': [2]}.items() %}{{ k }}={{ v | tojson }};{% endfor %}").unwrap(), "a=1;b=[2];");
+    assert_eq!(render("{{ {'name': 'f', 'x': none} | tojson }}").unwrap(), "{\"name\": \"f\", \"x\": null}");
+    assert_eq!(render("{{ 'x' if tools is defined else 'y' }}{{ 7 // 2 }}{{ 'ab' ~ 1 }}").unwrap(), "y3ab1");
+    assert_eq!(render("{% for m in messages if m.role != 'user' %}x{% else %}none{% endfor %}").unwrap(), "none");
+    assert!(render("{% if x %}").is_err());
+    assert_eq!(render("{{ '  x '.strip() }}{% for m in messages %}{% if loop.first %}{% continue %}{% endif %}{% endfor %}").unwrap(), "x");
+}
+
@@ -1,4 +1,5 @@
 use std::{slice, sync::Arc, vec};
+#[derive(Clone)]
 pub struct Tensor<T> {
     data: Arc<Box<[T]>>,
     shape: Vec<usize>,
@@ -7,17 +8,17 @@ pub struct Tensor<T> {
 }
 
 impl<T: Copy + Clone + Default> Tensor<T> {
-    pub fn new(data: Vec<T>, shape: &Vec<usize>) -> Self {
+    pub fn new(data: Vec<T>, shape: &[usize]) -> Self {
         let length = data.len();
         Tensor {
-            data: Arc::new(data.into_boxed_slice().try_into().unwrap()),
-            shape: shape.clone(),
+            data: Arc::new(data.into_boxed_slice()),
+            shape: shape.to_vec(),
             offset: 0,
             length,
         }
     }
 
-    pub fn default(shape: &Vec<usize>) -> Self {
+    pub fn default(shape: &[usize]) -> Self {
         let length = shape.iter().product();
         let data = vec![T::default(); length];
         Self::new(data, shape)
@@ -32,6 +33,12 @@ impl<T: Copy + Clone + Default> Tensor<T> {
         slice::from_raw_parts_mut(ptr, self.length)
     }
 
+    // Detach from any other tensor sharing the same storage (copy-on-write),
+    // so that writes through `data_mut` are not observed by the others.
+    pub fn make_unique(&mut self) {
+        Arc::make_mut(&mut self.data);
+    }
+
     pub fn shape(&self) -> &Vec<usize> {
         &self.shape
     }
@@ -41,22 +48,22 @@ impl<T: Copy + Clone + Default> Tensor<T> {
     }
 
     // Reinterpret the tensor as a new shape while preserving total size.
-    pub fn reshape(&mut self, new_shape: &Vec<usize>) -> &mut Self {
+    pub fn reshape(&mut self, new_shape: &[usize]) -> &mut Self {
         let new_length: usize = new_shape.iter().product();
         if new_length != self.length {
             let old_shape = self.shape.clone();
             panic!("New shape {new_shape:?} does not match tensor of {old_shape:?}");
         }
-        self.shape = new_shape.clone();
+        self.shape = new_shape.to_vec();
         self
     }
 
-    pub fn slice(&self, start: usize, shape: &Vec<usize>) -> Self {
+    pub fn slice(&self, start: usize, shape: &[usize]) -> Self {
         let new_length: usize = shape.iter().product();
         assert!(self.offset + start + new_length <= self.length);
         Tensor {
             data: self.data.clone(),
-            shape: shape.clone(),
+            shape: shape.to_vec(),
             offset: self.offset + start,
             length: new_length,
         }
@@ -73,7 +80,7 @@ impl<T: Copy + Clone + Default> Tensor<T> {
             }
         }
         Tensor {
-            data:Arc::new(data.into_boxed_slice().try_into().unwrap()),
+            data:Arc::new(data.into_boxed_slice()),
             shape:shape.clone(),
             offset:0,
             length:self.length,
@@ -92,7 +99,7 @@ impl Tensor<f32> {
         let a = self.data();
         let b = other.data();
         
-        return a.iter().zip(b).all(|(x, y)| float_eq(x, y, rel));
+        a.iter().zip(b).all(|(x, y)| float_eq(x, y, rel))
     }
     #[allow(unused)]
     pub fn print(&self){